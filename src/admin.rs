@@ -0,0 +1,316 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, debug, error, warn};
+
+use crate::{IpNetwork, NoProxy};
+use crate::{http, pac};
+use crate::stats::ProxyStats;
+
+/// What the admin listener needs to generate `/proxy.pac`
+pub(crate) struct PacSettings {
+    /// Address the proxy listener is bound to
+    pub listen_addr: SocketAddr,
+    /// Destinations the PAC file sends `DIRECT`
+    pub no_proxy: NoProxy,
+    /// Configured script served instead of the generated one
+    pub script: Option<String>,
+}
+
+/// Who may use the admin endpoints
+pub(crate) struct AdminAccess {
+    /// Credentials required with HTTP Basic auth, none when `None`
+    pub auth: Option<(String, String)>,
+    /// Client networks allowed to connect, any when empty
+    pub allowed_networks: Vec<IpNetwork>,
+}
+
+impl AdminAccess {
+    fn allows_peer(&self, peer: SocketAddr) -> bool {
+        self.allowed_networks.is_empty() || self.allowed_networks.iter().any(|network| network.contains(peer.ip()))
+    }
+
+    fn allows_request(&self, request: &str) -> bool {
+        let Some((user, password)) = &self.auth else {
+            return true;
+        };
+        let credentials = http::header_value(request, "Authorization")
+            .and_then(|value| value.trim().strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded.trim()).ok());
+        let Some((given_user, given_password)) = credentials.as_deref().and_then(|credentials| {
+            let colon = credentials.iter().position(|&b| b == b':')?;
+            Some((&credentials[..colon], &credentials[colon + 1..]))
+        }) else {
+            return false;
+        };
+        // Both halves are always compared, so the time taken doesn't tell which was wrong
+        let user_matches = constant_time_eq(given_user, user.as_bytes());
+        let password_matches = constant_time_eq(given_password, password.as_bytes());
+        user_matches & password_matches
+    }
+}
+
+/// Compare two byte strings in time depending only on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Serve the admin endpoints (`/metrics`, `/proxy-status` and optionally `/proxy.pac`) on the given address
+pub(crate) async fn serve(
+    addr: String,
+    stats: Arc<ProxyStats>,
+    pac: Option<Arc<PacSettings>>,
+    access: Arc<AdminAccess>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| anyhow!("Failed to bind admin listener to {}: {}", addr, e))?;
+    info!("Admin listener serving /metrics and /proxy-status on {}", addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept admin connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        if !access.allows_peer(peer) {
+            warn!("Refusing admin connection from {}", peer);
+            tokio::spawn(async move {
+                let _ = write_response(stream, "403 Forbidden", "", "Forbidden\n").await;
+            });
+            continue;
+        }
+
+        let stats = stats.clone();
+        let pac = pac.clone();
+        let access = access.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin_request(stream, &stats, pac.as_deref(), &access).await {
+                debug!("Error handling admin request from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer a single admin request and close the connection
+async fn handle_admin_request(
+    mut stream: TcpStream,
+    stats: &ProxyStats,
+    pac: Option<&PacSettings>,
+    access: &AdminAccess,
+) -> Result<()> {
+    let mut buf = [0; 1024];
+    let n = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        stream.read(&mut buf)
+    ).await.map_err(|_| anyhow!("Timeout reading admin request"))??;
+
+    let req = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = req.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    if !access.allows_request(&req) {
+        debug!("Rejecting admin request without valid credentials");
+        let challenge = "WWW-Authenticate: Basic realm=\"forward-proxy admin\"\r\n";
+        return write_response(stream, "401 Unauthorized", challenge, "Unauthorized\n").await;
+    }
+
+    let (status, content_type, body) = match (method, path, pac) {
+        ("GET", "/metrics", _) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            stats.encode()?,
+        ),
+        ("GET", "/proxy-status", _) => (
+            "200 OK",
+            "text/plain",
+            render_status(stats),
+        ),
+        ("GET", "/proxy.pac", Some(pac)) => (
+            "200 OK",
+            pac::CONTENT_TYPE,
+            render_pac(&stream, pac)?,
+        ),
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Write a plain-text response, `headers` being extra header lines each ending in CRLF
+async fn write_response(mut stream: TcpStream, status: &str, headers: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, headers, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Render the active connections, one per line
+fn render_status(stats: &ProxyStats) -> String {
+    let connections = stats.active_connections_snapshot();
+    let mut body = format!("active_connections {}\n", connections.len());
+    for conn in connections {
+        body.push_str(&format!(
+            "id={} client={} target={} upstream={} bytes_up={} bytes_down={} age_secs={}\n",
+            conn.id,
+            conn.client_addr,
+            conn.target.as_deref().unwrap_or("-"),
+            conn.upstream_addr.map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+            conn.bytes_up,
+            conn.bytes_down,
+            conn.age.as_secs(),
+        ));
+    }
+    body
+}
+
+/// Render the PAC file for a client of the admin listener
+///
+/// A configured script is served as is. Otherwise, when the proxy listens on
+/// a wildcard address, clients are pointed at the address they reached the
+/// admin listener on.
+fn render_pac(stream: &TcpStream, pac: &PacSettings) -> Result<String> {
+    if let Some(script) = &pac.script {
+        return Ok(script.clone());
+    }
+    let mut proxy_addr = pac.listen_addr;
+    if proxy_addr.ip().is_unspecified() {
+        proxy_addr.set_ip(stream.local_addr()?.ip().to_canonical());
+    }
+    Ok(pac::render(proxy_addr, &pac.no_proxy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn open() -> AdminAccess {
+        AdminAccess {
+            auth: None,
+            allowed_networks: Vec::new(),
+        }
+    }
+
+    fn pac_for(listen_addr: &str) -> PacSettings {
+        PacSettings {
+            listen_addr: listen_addr.parse().unwrap(),
+            no_proxy: NoProxy::parse("internal.test,10.0.0.0/8"),
+            script: None,
+        }
+    }
+
+    /// Send `request` to the admin handler over loopback, returning the whole response
+    async fn fetch(request: &str, pac: Option<&PacSettings>, access: &AdminAccess) -> String {
+        let (listener, addr) = listener().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stats = ProxyStats::new(None).unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle_admin_request(stream, &stats, pac, access).await.unwrap();
+        read_to_end(&mut client).await
+    }
+
+    #[tokio::test]
+    async fn serves_pac_pointing_at_the_proxy() {
+        let pac = pac_for("127.0.0.1:3128");
+        let response = fetch("GET /proxy.pac HTTP/1.1\r\nHost: localhost\r\n\r\n", Some(&pac), &open()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: application/x-ns-proxy-autoconfig\r\n"), "{}", response);
+        assert!(response.contains("function FindProxyForURL(url, host)"), "{}", response);
+        assert!(response.contains("return \"PROXY 127.0.0.1:3128\";"), "{}", response);
+        assert!(
+            response.contains("if (host == \"internal.test\" || dnsDomainIs(host, \".internal.test\")) return \"DIRECT\";"),
+            "{}", response
+        );
+        assert!(response.contains("isInNet(host, \"10.0.0.0\", \"255.0.0.0\")) return \"DIRECT\";"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn pac_of_wildcard_listener_names_the_address_reached() {
+        let pac = pac_for("0.0.0.0:3128");
+        let response = fetch("GET /proxy.pac HTTP/1.1\r\n\r\n", Some(&pac), &open()).await;
+        assert!(response.contains("return \"PROXY 127.0.0.1:3128\";"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn pac_is_only_served_when_enabled() {
+        let response = fetch("GET /proxy.pac HTTP/1.1\r\n\r\n", None, &open()).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    fn protected() -> AdminAccess {
+        AdminAccess {
+            auth: Some(("ops".to_string(), "s3cret".to_string())),
+            allowed_networks: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_valid_credentials() {
+        let wrong = BASE64.encode("ops:guess");
+        for authorization in [String::new(), format!("Authorization: Basic {}\r\n", wrong), "Authorization: Bearer s3cret\r\n".to_string()] {
+            let request = format!("GET /metrics HTTP/1.1\r\nHost: localhost\r\n{}\r\n", authorization);
+            let response = fetch(&request, None, &protected()).await;
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+            assert!(response.contains("\r\nWWW-Authenticate: Basic realm=\"forward-proxy admin\"\r\n"), "{}", response);
+            assert!(!response.contains("proxy_"), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_requests_with_valid_credentials() {
+        let request = format!("GET /metrics HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic {}\r\n\r\n", BASE64.encode("ops:s3cret"));
+        let response = fetch(&request, None, &protected()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("proxy_connections_total"), "{}", response);
+    }
+
+    /// Response to `GET /proxy-status` from an admin listener allowing `networks`
+    async fn status_allowing(networks: &str) -> String {
+        // Reserve a free port for the listener
+        let (reserved, addr) = listener().await;
+        drop(reserved);
+        let access = AdminAccess {
+            auth: None,
+            allowed_networks: networks.split(',').map(|network| network.parse().unwrap()).collect(),
+        };
+        let stats = Arc::new(ProxyStats::new(None).unwrap());
+        let admin = tokio::spawn(serve(addr.to_string(), stats, None, Arc::new(access)));
+        let mut client = loop {
+            match TcpStream::connect(addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        client.write_all(b"GET /proxy-status HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let response = read_to_end(&mut client).await;
+        admin.abort();
+        response
+    }
+
+    #[tokio::test]
+    async fn only_allowed_networks_reach_the_endpoints() {
+        let response = status_allowing("10.0.0.0/8,192.168.0.0/16").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        let response = status_allowing("10.0.0.0/8,127.0.0.0/8").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("active_connections 0\n"), "{}", response);
+    }
+}
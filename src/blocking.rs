@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use anyhow::{Result, anyhow};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::oneshot;
+
+use crate::{ProxyConfig, ProxyStats, spawn_proxy, start_proxy};
+
+/// Run the proxy to completion on an internal Tokio runtime, blocking the calling thread
+pub fn run_blocking(config: ProxyConfig) -> Result<()> {
+    new_runtime()?.block_on(start_proxy(config))
+}
+
+/// Start the proxy on a dedicated runtime thread, returning once it is ready to accept connections
+pub fn spawn_blocking_handle(config: ProxyConfig) -> Result<BlockingProxyHandle> {
+    let runtime = new_runtime()?;
+    let mut handle = {
+        let _guard = runtime.enter();
+        spawn_proxy(config)?
+    };
+    let local_addr = runtime.block_on(handle.ready())?;
+    let stats = handle.stats();
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let thread = thread::Builder::new()
+        .name("forward-proxy".to_string())
+        .spawn(move || {
+            // Dropping the runtime afterwards cancels every remaining connection task
+            runtime.block_on(async move {
+                tokio::select! {
+                    result = handle.join() => result,
+                    _ = stop_rx => Ok(()),
+                }
+            })
+        })?;
+
+    Ok(BlockingProxyHandle {
+        stats,
+        local_addr,
+        stop: Some(stop_tx),
+        thread: Some(thread),
+    })
+}
+
+fn new_runtime() -> Result<Runtime> {
+    Ok(Builder::new_multi_thread().enable_all().build()?)
+}
+
+/// Handle to a proxy running on its own runtime thread
+///
+/// Dropping the handle stops the proxy.
+pub struct BlockingProxyHandle {
+    stats: Arc<ProxyStats>,
+    local_addr: SocketAddr,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<Result<()>>>,
+}
+
+impl BlockingProxyHandle {
+    /// Statistics collected by the running proxy
+    pub fn stats(&self) -> Arc<ProxyStats> {
+        self.stats.clone()
+    }
+
+    /// Address the proxy is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Terminate every active connection whose destination is `host`, returning how many were closed
+    pub fn kill_host(&self, host: &str) -> usize {
+        self.stats.abort_connections_to(host)
+    }
+
+    /// Stop the proxy, closing open connections, and wait for its thread to exit
+    pub fn shutdown(mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            // The proxy may already have exited on its own
+            let _ = stop.send(());
+        }
+        self.wait()
+    }
+
+    /// Block until the proxy exits on its own, e.g. after SIGTERM
+    pub fn join(mut self) -> Result<()> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| anyhow!("Proxy thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BlockingProxyHandle {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        let _ = self.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    use crate::testing::*;
+
+    /// Open a tunnel through the proxy at `addr` and check it echoes
+    fn tunnel_through(addr: SocketAddr) {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"CONNECT example.test:443 HTTP/1.1\r\nHost: example.test:443\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        client.write_all(b"blocking").unwrap();
+        let mut echoed = [0; 8];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"blocking");
+    }
+
+    /// Upstream proxy running on a runtime of its own, like the one of an unrelated application
+    fn upstream(runtime: &Runtime) -> SocketAddr {
+        runtime.block_on(tunnel_upstream()).0
+    }
+
+    #[test]
+    fn run_blocking_serves_from_a_background_thread() {
+        let runtime = new_runtime().unwrap();
+        let mut config = config(upstream(&runtime));
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        config.local_port = port;
+        thread::spawn(move || run_blocking(config));
+
+        // Nothing signals readiness, so wait for the listener to come up
+        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "proxy didn't start listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+        tunnel_through(addr);
+    }
+
+    #[test]
+    fn blocking_handle_serves_until_shut_down() {
+        let runtime = new_runtime().unwrap();
+        let handle = spawn_blocking_handle(config(upstream(&runtime))).unwrap();
+        let addr = handle.local_addr();
+        tunnel_through(addr);
+        assert!(handle.stats().encode().unwrap().contains("connections"));
+
+        handle.shutdown().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Size of the buffer requests are read into
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Client connection, buffered so request heads can be read without consuming what follows
+///
+/// Works like `tokio::io::BufReader`, except that [`fill_more`](Self::fill_more)
+/// can read past bytes already buffered without consuming them, e.g. to wait
+/// for the rest of a request line split across segments. Writes go straight
+/// to the connection.
+pub(crate) struct ClientStream {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+}
+
+impl ClientStream {
+    pub(crate) fn new(stream: TcpStream) -> Self {
+        ClientStream {
+            stream,
+            buf: vec![0; BUFFER_SIZE],
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Bytes read from the connection but not consumed yet
+    pub(crate) fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    /// Read more from the connection behind what is buffered, returning how much; 0 at EOF
+    pub(crate) async fn fill_more(&mut self) -> io::Result<usize> {
+        std::future::poll_fn(|cx| self.poll_fill_more(cx)).await
+    }
+
+    fn poll_fill_more(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if self.pos > 0 {
+            self.buf.copy_within(self.pos..self.filled, 0);
+            self.filled -= self.pos;
+            self.pos = 0;
+        }
+        if self.filled == self.buf.len() {
+            self.buf.resize(self.buf.len() * 2, 0);
+        }
+        let mut read = ReadBuf::new(&mut self.buf[self.filled..]);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read))?;
+        let n = read.filled().len();
+        self.filled += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, dst: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Nothing buffered and a large read, skip the copy
+        if this.pos == this.filled && dst.remaining() >= this.buf.len() {
+            return Pin::new(&mut this.stream).poll_read(cx, dst);
+        }
+        let available = ready!(Pin::new(&mut *this).poll_fill_buf(cx))?;
+        let n = available.len().min(dst.remaining());
+        dst.put_slice(&available[..n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for ClientStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.filled {
+            let mut read = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read))?;
+            this.filled = read.filled().len();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.filled]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.filled);
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
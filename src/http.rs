@@ -0,0 +1,1182 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::Sleep;
+
+/// Find the end of an HTTP head, returning the index just past the blank line
+///
+/// Lines may also end in a bare LF, as sent by some older clients
+/// (RFC 9112 section 2.2).
+pub(crate) fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.iter().enumerate().find_map(|(pos, &byte)| {
+        if byte != b'\n' {
+            return None;
+        }
+        match &buf[pos + 1..] {
+            [b'\n', ..] => Some(pos + 2),
+            [b'\r', b'\n', ..] => Some(pos + 3),
+            _ => None,
+        }
+    })
+}
+
+/// Rewrite lines of a head ending in a bare LF to end in CRLF
+pub(crate) fn normalize_line_endings(head: Vec<u8>) -> Vec<u8> {
+    let bare_lf = |pos: usize| head[pos] == b'\n' && (pos == 0 || head[pos - 1] != b'\r');
+    if !(0..head.len()).any(bare_lf) {
+        return head;
+    }
+    let mut normalized = Vec::with_capacity(head.len() + 16);
+    for (pos, &byte) in head.iter().enumerate() {
+        if bare_lf(pos) {
+            normalized.push(b'\r');
+        }
+        normalized.push(byte);
+    }
+    normalized
+}
+
+/// Read an HTTP head from `stream`, up to `max_size` bytes
+///
+/// Returns the head (including the terminating blank line, line endings
+/// normalized to CRLF) and any bytes read past it.
+pub(crate) async fn read_head<S: AsyncRead + Unpin>(stream: &mut S, max_size: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(end) = find_head_end(&buf) {
+            // The last read may have carried the head past the limit along with its end
+            if end > max_size {
+                return Err(HeadTooLarge { limit: max_size }.into());
+            }
+            let rest = buf.split_off(end);
+            return Ok((normalize_line_endings(buf), rest));
+        }
+        if buf.len() >= max_size {
+            return Err(HeadTooLarge { limit: max_size }.into());
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before end of HTTP head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Error for an HTTP head larger than the allowed size
+#[derive(Debug)]
+pub(crate) struct HeadTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for HeadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP head exceeds {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for HeadTooLarge {}
+
+/// Error for an HTTP head with more header fields than allowed
+#[derive(Debug)]
+pub(crate) struct TooManyHeaders {
+    pub limit: usize,
+}
+
+impl fmt::Display for TooManyHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP head has more than {} header fields", self.limit)
+    }
+}
+
+impl std::error::Error for TooManyHeaders {}
+
+/// Form of the request target in a request line (RFC 9112 section 3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestTarget {
+    /// A path and query, e.g. `/index.html`
+    Origin,
+    /// A full URI, e.g. `http://example.com/index.html`
+    Absolute,
+    /// A host and port, only used by `CONNECT`
+    Authority,
+    /// `*`, only used by server-wide `OPTIONS`
+    Asterisk,
+}
+
+impl RequestTarget {
+    /// Classify a request target
+    pub(crate) fn of(target: &str) -> Self {
+        if target == "*" {
+            RequestTarget::Asterisk
+        } else if target.starts_with('/') {
+            RequestTarget::Origin
+        } else if target.contains("://") {
+            RequestTarget::Absolute
+        } else {
+            RequestTarget::Authority
+        }
+    }
+}
+
+/// Longest method token accepted at the start of a request
+const MAX_METHOD_LEN: usize = 32;
+
+/// Whether the first bytes a client sent could start an HTTP request
+///
+/// Checks for a method token (RFC 9110 `tchar`s) followed by a space. A
+/// prefix too short to contain the space is accepted if it is all `tchar`s.
+pub(crate) fn looks_like_http(prefix: &[u8]) -> bool {
+    let is_tchar = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b);
+    let token_len = prefix.iter().take_while(|b| is_tchar(b)).count();
+    if token_len == 0 || token_len > MAX_METHOD_LEN {
+        return false;
+    }
+    match prefix.get(token_len) {
+        Some(b) => *b == b' ',
+        None => true,
+    }
+}
+
+/// Parse the status code from the status line of a response head
+pub(crate) fn status_code(head: &str) -> Option<u16> {
+    let status_line = head.lines().next()?;
+    status_line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Replace the status code of a response head, along with its reason phrase
+///
+/// The new reason is the standard one for `status`, or empty for codes without one.
+pub(crate) fn with_status(head: &str, status: u16) -> String {
+    let (status_line, rest) = head.split_once("\r\n").unwrap_or((head, ""));
+    let version = status_line.split_whitespace().next().unwrap_or("HTTP/1.1");
+    let reason = hyper::StatusCode::from_u16(status).ok().and_then(|code| code.canonical_reason()).unwrap_or("");
+    format!("{} {} {}\r\n{}", version, status, reason, rest)
+}
+
+/// Number of header fields in a head, the start line aside
+pub(crate) fn header_count(head: &str) -> usize {
+    head.lines().skip(1).filter(|line| !line.is_empty()).count()
+}
+
+/// Iterate over the values of every header called `name` in a head
+pub(crate) fn header_values<'a>(head: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    head.lines().skip(1).filter_map(move |line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// The value of the first header called `name` in a head
+pub(crate) fn header_value<'a>(head: &'a str, name: &'a str) -> Option<&'a str> {
+    header_values(head, name).next()
+}
+
+/// Read and discard a response body delimited by `Content-Length`
+///
+/// `already_read` holds body bytes that were read along with the head.
+pub(crate) async fn discard_body<S: AsyncRead + Unpin>(stream: &mut S, head: &str, already_read: usize) -> Result<()> {
+    let length: u64 = match header_value(head, "Content-Length") {
+        Some(length) => length.parse().map_err(|_| anyhow!("Invalid Content-Length: {}", length))?,
+        None => 0,
+    };
+    let remaining = length.saturating_sub(already_read as u64);
+    let copied = tokio::io::copy(&mut stream.take(remaining), &mut tokio::io::sink()).await?;
+    if copied < remaining {
+        return Err(anyhow!("Connection closed before end of response body"));
+    }
+    Ok(())
+}
+
+/// Largest chunk-size or trailer line accepted in a chunked body
+const MAX_CHUNK_LINE: u64 = 8 * 1024;
+
+/// Largest trailer section accepted after the last chunk
+const MAX_TRAILER_SIZE: u64 = 16 * 1024;
+
+/// Read an HTTP head from a buffered reader, up to `max_size` bytes
+///
+/// Only the head (including the terminating blank line) is consumed, any
+/// following bytes stay buffered. Line endings are normalized to CRLF.
+/// Returns `None` if the connection was closed before any bytes arrived.
+pub(crate) async fn read_head_buffered<R: AsyncBufRead + Unpin>(reader: &mut R, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut head = Vec::with_capacity(1024);
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("Connection closed before end of HTTP head"));
+        }
+
+        // The terminator may straddle the previous and the new bytes
+        let search_from = head.len().saturating_sub(3);
+        let previous_len = head.len();
+        head.extend_from_slice(available);
+        if let Some(end) = find_head_end(&head[search_from..]).map(|end| search_from + end) {
+            if end > max_size {
+                return Err(HeadTooLarge { limit: max_size }.into());
+            }
+            reader.consume(end - previous_len);
+            head.truncate(end);
+            return Ok(Some(normalize_line_endings(head)));
+        }
+
+        let consumed = available.len();
+        reader.consume(consumed);
+        if head.len() > max_size {
+            return Err(HeadTooLarge { limit: max_size }.into());
+        }
+    }
+}
+
+/// How the end of a message body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyLength {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes
+    Length(u64),
+    /// The body uses chunked transfer coding
+    Chunked,
+    /// The body extends until the connection is closed
+    UntilClose,
+}
+
+/// Check that a request head frames its body unambiguously
+///
+/// Rejects the classic request smuggling vectors: `Content-Length` together
+/// with `Transfer-Encoding`, conflicting or malformed `Content-Length` values,
+/// a `Transfer-Encoding` not ending in `chunked`, and header lines that
+/// intermediaries may parse differently (whitespace before the colon, obsolete
+/// line folding).
+pub(crate) fn validate_request_framing(head: &str) -> Result<(), &'static str> {
+    for line in head.lines().skip(1).take_while(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            return Err("obsolete line folding");
+        }
+        let (name, _) = line.split_once(':').ok_or("malformed header line")?;
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err("malformed header name");
+        }
+    }
+
+    let mut lengths = header_values(head, "Content-Length").flat_map(|value| value.split(',')).map(str::trim);
+    let length = lengths.next();
+    if let Some(length) = length {
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return Err("invalid Content-Length");
+        }
+        if lengths.any(|other| other != length) {
+            return Err("conflicting Content-Length headers");
+        }
+    }
+
+    if header_value(head, "Transfer-Encoding").is_some() {
+        if length.is_some() {
+            return Err("both Content-Length and Transfer-Encoding");
+        }
+        if !is_chunked(head) {
+            return Err("Transfer-Encoding does not end in chunked");
+        }
+    }
+    Ok(())
+}
+
+/// Determine the framing of a request body from its head
+pub(crate) fn request_body_length(head: &str) -> Result<BodyLength> {
+    if is_chunked(head) {
+        return Ok(BodyLength::Chunked);
+    }
+    match content_length(head)? {
+        Some(0) | None => Ok(BodyLength::Empty),
+        Some(length) => Ok(BodyLength::Length(length)),
+    }
+}
+
+/// Determine the framing of a response body from its head and the request method
+pub(crate) fn response_body_length(method: &str, status: u16, head: &str) -> Result<BodyLength> {
+    if method.eq_ignore_ascii_case("HEAD") || (100..200).contains(&status) || status == 204 || status == 304 {
+        return Ok(BodyLength::Empty);
+    }
+    if is_chunked(head) {
+        return Ok(BodyLength::Chunked);
+    }
+    match content_length(head)? {
+        Some(0) => Ok(BodyLength::Empty),
+        Some(length) => Ok(BodyLength::Length(length)),
+        None => Ok(BodyLength::UntilClose),
+    }
+}
+
+fn is_chunked(head: &str) -> bool {
+    header_values(head, "Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn content_length(head: &str) -> Result<Option<u64>> {
+    header_value(head, "Content-Length")
+        .map(|length| length.parse().map_err(|_| anyhow!("Invalid Content-Length: {}", length)))
+        .transpose()
+}
+
+/// Whether requests with `method` can be repeated without further effect (RFC 9110)
+pub(crate) fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+}
+
+/// Whether the sender of a head wants the connection kept open afterwards
+///
+/// HTTP/1.1 defaults to persistent connections, HTTP/1.0 requires an explicit
+/// `keep-alive`. `Proxy-Connection` is honored as well since clients send it
+/// to proxies.
+pub(crate) fn is_keep_alive(head: &str) -> bool {
+    let start_line = head.lines().next().unwrap_or_default();
+    let http_11 = start_line.contains("HTTP/1.1");
+    let mut tokens = header_values(head, "Connection")
+        .chain(header_values(head, "Proxy-Connection"))
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    if http_11 {
+        !tokens.any(|token| token.eq_ignore_ascii_case("close"))
+    } else {
+        tokens.any(|token| token.eq_ignore_ascii_case("keep-alive"))
+    }
+}
+
+/// How long the sender keeps an idle connection open, from a `Keep-Alive: timeout=N` header
+pub(crate) fn keep_alive_timeout(head: &str) -> Option<std::time::Duration> {
+    header_values(head, "Keep-Alive")
+        .flat_map(|value| value.split(','))
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("timeout"))
+        .and_then(|(_, secs)| secs.trim().parse().ok())
+        .map(std::time::Duration::from_secs)
+}
+
+/// Remove the headers that only apply to the connection they were received on
+///
+/// Drops `Connection`, `Proxy-Connection` and `Keep-Alive` along with any
+/// header `Connection` names, except those framing the message.
+pub(crate) fn strip_hop_by_hop(head: &str) -> String {
+    let hop_by_hop = hop_by_hop_names(head);
+    let lines: Vec<&str> = head
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .enumerate()
+        .filter(|(i, line)| *i == 0 || !is_named(line, &hop_by_hop))
+        .map(|(_, line)| line)
+        .collect();
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
+/// Rewrite a request head for the upstream proxy in a single pass
+///
+/// Same as stripping the hop-by-hop headers, setting `Connection` and
+/// `Proxy-Connection` to `connection` and replacing `Proxy-Authorization`
+/// with `auth`, or dropping it when `None`. A head that already reads that
+/// way is forwarded unchanged, byte for byte.
+pub(crate) fn forward_head(head: &str, connection: &str, auth: Option<&str>) -> String {
+    if is_forwardable_as_is(head, connection, auth) {
+        return head.to_string();
+    }
+    let mut dropped = hop_by_hop_names(head);
+    dropped.push("Proxy-Authorization");
+    let mut forwarded = String::with_capacity(head.len() + 64);
+    for (i, line) in head.trim_end_matches("\r\n").split("\r\n").enumerate() {
+        if i == 0 || !is_named(line, &dropped) {
+            forwarded.push_str(line);
+            forwarded.push_str("\r\n");
+        }
+    }
+    for name in ["Connection", "Proxy-Connection"] {
+        forwarded.push_str(&format!("{}: {}\r\n", name, connection));
+    }
+    if let Some(auth) = auth {
+        forwarded.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    forwarded.push_str("\r\n");
+    forwarded
+}
+
+/// Whether rewriting a request head with `forward_head` would leave its meaning unchanged
+///
+/// Its only hop-by-hop headers are a single `Connection` and `Proxy-Connection`
+/// saying `connection`, which may be left out when that is the default of the
+/// HTTP version, and it carries exactly the `Proxy-Authorization` wanted.
+fn is_forwardable_as_is(head: &str, connection: &str, auth: Option<&str>) -> bool {
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+    let default = match lines.next() {
+        Some(request_line) if request_line.ends_with("HTTP/1.0") => "close",
+        _ => "keep-alive",
+    };
+    let (mut connection_seen, mut proxy_connection_seen, mut auth_seen) = (false, false, false);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        let (seen, matches) = if name.eq_ignore_ascii_case("Connection") {
+            (&mut connection_seen, value.eq_ignore_ascii_case(connection))
+        } else if name.eq_ignore_ascii_case("Proxy-Connection") {
+            (&mut proxy_connection_seen, value.eq_ignore_ascii_case(connection))
+        } else if name.eq_ignore_ascii_case("Proxy-Authorization") {
+            (&mut auth_seen, auth == Some(value))
+        } else if name.eq_ignore_ascii_case("Keep-Alive") {
+            return false;
+        } else {
+            continue;
+        };
+        // The rewrite would collapse repeated headers into one
+        if *seen || !matches {
+            return false;
+        }
+        *seen = true;
+    }
+    let implied = connection.eq_ignore_ascii_case(default);
+    (implied || connection_seen && proxy_connection_seen) && auth_seen == auth.is_some()
+}
+
+/// Names of the hop-by-hop headers of a head, those `Connection` lists included
+fn hop_by_hop_names(head: &str) -> Vec<&str> {
+    let mut hop_by_hop: Vec<&str> = vec!["Connection", "Proxy-Connection", "Keep-Alive"];
+    hop_by_hop.extend(
+        header_values(head, "Connection")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !["Content-Length", "Transfer-Encoding", "Host"].iter().any(|framing| name.eq_ignore_ascii_case(framing))),
+    );
+    hop_by_hop
+}
+
+/// Whether a header line is one of the headers `names`
+fn is_named(line: &str, names: &[&str]) -> bool {
+    line.split_once(':').is_some_and(|(name, _)| names.iter().any(|named| name.trim().eq_ignore_ascii_case(named)))
+}
+
+/// Append `value` to the comma-separated list header `name`, adding the header if absent
+pub(crate) fn append_header_value(head: &str, name: &str, value: &str) -> String {
+    let mut lines: Vec<String> = head.trim_end_matches("\r\n").split("\r\n").map(str::to_string).collect();
+    let existing = lines.iter_mut().skip(1).rev().find(|line| {
+        line.split_once(':').is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+    });
+    match existing {
+        Some(line) => {
+            line.push_str(", ");
+            line.push_str(value);
+        }
+        None => lines.push(format!("{}: {}", name, value)),
+    }
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
+/// Set header `name` to `value`, replacing any existing headers of that name
+pub(crate) fn set_header(head: &str, name: &str, value: &str) -> String {
+    let head = remove_header(head, name);
+    format!("{}\r\n{}: {}\r\n\r\n", head.trim_end_matches("\r\n"), name, value)
+}
+
+/// Remove every header named `name`
+pub(crate) fn remove_header(head: &str, name: &str) -> String {
+    let lines: Vec<&str> = head
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .enumerate()
+        .filter(|(i, line)| *i == 0 || !line.split_once(':').is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name)))
+        .map(|(_, line)| line)
+        .collect();
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
+/// Headers that frame the message or belong to the proxy hop and can't be injected
+const RESERVED_HEADERS: [&str; 8] = [
+    "Content-Length",
+    "Transfer-Encoding",
+    "Host",
+    "Connection",
+    "Proxy-Connection",
+    "Keep-Alive",
+    "Upgrade",
+    "Proxy-Authorization",
+];
+
+/// Check that a header is safe to add to forwarded requests
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<()> {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(is_tchar) {
+        return Err(anyhow!("Invalid header name: {:?}", name));
+    }
+    if RESERVED_HEADERS.iter().any(|reserved| name.eq_ignore_ascii_case(reserved)) {
+        return Err(anyhow!("Header {} can't be set on forwarded requests", name));
+    }
+    if value.chars().any(|c| c == '\r' || c == '\n' || c == '\0') {
+        return Err(anyhow!("Invalid value for header {}: {:?}", name, value));
+    }
+    Ok(())
+}
+
+/// Rewrite a head so it tells the receiver the connection will be closed
+pub(crate) fn with_connection_close(head: &str) -> String {
+    let mut lines: Vec<&str> = head
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .filter(|line| {
+            let name = line.split_once(':').map_or("", |(name, _)| name.trim());
+            !["Connection", "Proxy-Connection", "Keep-Alive"]
+                .iter()
+                .any(|hop| name.eq_ignore_ascii_case(hop))
+        })
+        .collect();
+    lines.push("Connection: close");
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
+/// Headers whose values are hidden in captured responses
+const REDACTED_HEADERS: [&str; 6] = [
+    "Authorization",
+    "Proxy-Authorization",
+    "WWW-Authenticate",
+    "Proxy-Authenticate",
+    "Cookie",
+    "Set-Cookie",
+];
+
+/// Render up to `limit` bytes of a raw response as a hex and ASCII dump, with credentials redacted
+pub(crate) fn capture(raw: &[u8], limit: usize) -> String {
+    let head_end = find_head_end(raw).unwrap_or(raw.len());
+    let mut redacted = Vec::with_capacity(raw.len());
+    for (i, line) in raw[..head_end].split_inclusive(|&b| b == b'\n').enumerate() {
+        let name = line.iter().position(|&b| b == b':').map(|colon| String::from_utf8_lossy(&line[..colon]));
+        match name {
+            Some(name) if i > 0 && REDACTED_HEADERS.iter().any(|header| name.trim().eq_ignore_ascii_case(header)) => {
+                redacted.extend_from_slice(format!("{}: [redacted]\r\n", name.trim()).as_bytes());
+            }
+            _ => redacted.extend_from_slice(line),
+        }
+    }
+    redacted.extend_from_slice(&raw[head_end..]);
+
+    let shown = &redacted[..redacted.len().min(limit)];
+    let mut dump = String::new();
+    for (row, bytes) in shown.chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        dump.push_str(&format!("\n{:04x}  {:<47}  |{}|", row * 16, hex.join(" "), ascii));
+    }
+    if shown.len() < redacted.len() {
+        dump.push_str(&format!("\n({} more bytes not shown)", redacted.len() - shown.len()));
+    }
+    dump
+}
+
+/// Rewrite an absolute-form `http://` request head for sending straight to the origin server
+///
+/// Returns the origin's host and port (80 unless given) along with the head
+/// using an origin-form request target and without proxy-only headers.
+pub(crate) fn to_origin_form(head: &str) -> Option<(String, u16, String)> {
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+    let mut parts = lines.next()?.split_whitespace();
+    let (method, uri, version) = (parts.next()?, parts.next()?, parts.next()?);
+    let (authority, path) = split_absolute_uri(uri)?;
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => {
+            let (host, after) = v6.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 80,
+    };
+
+    let mut origin_head = format!("{} {} {}\r\n", method, path, version);
+    for line in lines {
+        let name = line.split_once(':').map_or("", |(name, _)| name.trim());
+        if name.eq_ignore_ascii_case("Proxy-Authorization") || name.eq_ignore_ascii_case("Proxy-Connection") {
+            continue;
+        }
+        origin_head.push_str(line);
+        origin_head.push_str("\r\n");
+    }
+    origin_head.push_str("\r\n");
+    Some((host.to_string(), port, origin_head))
+}
+
+/// Rewrite the request line of an absolute-form `http://` request head to origin-form
+///
+/// `Host` is set to the URI's authority, which takes precedence over any
+/// `Host` the client sent. Heads with another form of target are returned as is.
+pub(crate) fn with_origin_form_target(head: &str) -> String {
+    let Some((request_line, headers)) = head.split_once("\r\n") else {
+        return head.to_string();
+    };
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return head.to_string();
+    };
+    let Some((authority, path)) = split_absolute_uri(uri) else {
+        return head.to_string();
+    };
+    let rewritten = format!("{} {} {}\r\n{}", method, path, version, headers);
+    set_header(&rewritten, "Host", authority)
+}
+
+/// Whether every `Host` header of an absolute-form request names the authority of its URI
+///
+/// Hosts are compared ignoring case and a trailing dot, ports after applying
+/// the scheme's default. Requests without a `Host` header are consistent.
+pub(crate) fn host_matches_uri(head: &str, uri: &str) -> bool {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return false;
+    };
+    let default_port = if scheme.eq_ignore_ascii_case("https") { 443 } else { 80 };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let target = normalize_authority(authority, default_port);
+    target.is_some() && header_values(head, "Host").all(|host| normalize_authority(host, default_port) == target)
+}
+
+/// Lowercase host without a trailing dot and the port of an authority, `None` if the port is invalid
+fn normalize_authority(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let host = crate::host_from_authority(authority);
+    let port = match authority.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or("", |(_, after)| after),
+        None => authority.strip_prefix(host).unwrap_or_default(),
+    };
+    let port = match port.strip_prefix(':').unwrap_or(port) {
+        "" => default_port,
+        port => port.parse().ok()?,
+    };
+    Some((host.trim_end_matches('.').to_ascii_lowercase(), port))
+}
+
+/// Split an absolute-form `http://` URI into its authority, without userinfo, and an origin-form target
+fn split_absolute_uri(uri: &str) -> Option<(&str, String)> {
+    let scheme_len = "http://".len();
+    if !uri.get(..scheme_len)?.eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &uri[scheme_len..];
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    let path = path.split('#').next().unwrap_or_default();
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    Some((authority, path))
+}
+
+/// Error writing a relayed body onwards, as opposed to reading it
+#[derive(Debug)]
+pub(crate) struct BodyWriteFailed(pub io::Error);
+
+impl fmt::Display for BodyWriteFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to write message body: {}", self.0)
+    }
+}
+
+impl std::error::Error for BodyWriteFailed {}
+
+/// Why reading a request body was given up on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyLimitExceeded {
+    /// The body is larger than this many bytes
+    TooLarge(u64),
+    /// Nothing of the body arrived for this long
+    Idle(Duration),
+}
+
+impl BodyLimitExceeded {
+    /// The limit a failed body relay ran into, if that is why it failed
+    pub(crate) fn find(error: &anyhow::Error) -> Option<Self> {
+        error.chain().find_map(|cause| {
+            let inner = cause.downcast_ref::<io::Error>()?.get_ref()?;
+            inner.downcast_ref::<BodyLimitExceeded>().copied()
+        })
+    }
+}
+
+impl fmt::Display for BodyLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyLimitExceeded::TooLarge(limit) => write!(f, "request body larger than {} bytes", limit),
+            BodyLimitExceeded::Idle(timeout) => write!(f, "no request body received for {:?}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for BodyLimitExceeded {}
+
+/// Reader of a request body failing once it exceeds a size or stalls for too long
+///
+/// The size counts the body as sent, including any chunked framing. The idle
+/// timer starts whenever the client has nothing to read yet.
+pub(crate) struct BodyGuard<R> {
+    inner: R,
+    read: u64,
+    max_bytes: Option<u64>,
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> BodyGuard<R> {
+    pub(crate) fn new(inner: R, max_bytes: Option<u64>, idle_timeout: Option<Duration>) -> Self {
+        BodyGuard {
+            inner,
+            read: 0,
+            max_bytes,
+            idle_timeout,
+            idle: None,
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for BodyGuard<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+            Poll::Ready(Ok(buf)) => {
+                this.idle = None;
+                match this.max_bytes {
+                    Some(max) if !buf.is_empty() && this.read >= max => {
+                        Poll::Ready(Err(io::Error::other(BodyLimitExceeded::TooLarge(max))))
+                    }
+                    Some(max) => {
+                        // Whatever is past the limit fails the next read
+                        let allowed = usize::try_from(max - this.read).unwrap_or(usize::MAX);
+                        Poll::Ready(Ok(&buf[..buf.len().min(allowed)]))
+                    }
+                    None => Poll::Ready(Ok(buf)),
+                }
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                let Some(timeout) = this.idle_timeout else {
+                    return Poll::Pending;
+                };
+                let idle = this.idle.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                match Future::poll(idle.as_mut(), cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        BodyLimitExceeded::Idle(timeout),
+                    ))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.read += amt as u64;
+        Pin::new(&mut this.inner).consume(amt);
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for BodyGuard<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Relay a message body with the given framing, returning the number of bytes written
+///
+/// The writer is flushed whenever the reader has nothing more to offer yet, so
+/// a buffered writer coalesces small writes without holding data back.
+/// Failures of the writer are reported as [`BodyWriteFailed`].
+pub(crate) async fn relay_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let relayed = match length {
+        BodyLength::Empty => 0,
+        BodyLength::Length(length) => {
+            let copied = copy_flushing(&mut reader.take(length), writer).await?;
+            if copied < length {
+                return Err(anyhow!("Connection closed after {} of {} body bytes", copied, length));
+            }
+            copied
+        }
+        BodyLength::Chunked => relay_chunked(reader, writer).await?,
+        BodyLength::UntilClose => copy_flushing(reader, writer).await?,
+    };
+    writer.flush().await.map_err(BodyWriteFailed)?;
+    Ok(relayed)
+}
+
+/// Copy everything from `reader` to `writer`, flushing whenever the reader is idle
+async fn copy_flushing<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+    loop {
+        flush_if_idle(reader, writer).await?;
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(total);
+        }
+        let n = buf.len();
+        writer.write_all(buf).await.map_err(BodyWriteFailed)?;
+        reader.consume(n);
+        total += n as u64;
+    }
+}
+
+/// Flush `writer` if `reader` has no data ready, instead of holding buffered writes while waiting
+async fn flush_if_idle<R, W>(reader: &mut R, writer: &mut W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let idle = std::future::poll_fn(|cx| match Pin::new(&mut *reader).poll_fill_buf(cx) {
+        Poll::Ready(Ok(_)) => Poll::Ready(Ok(false)),
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        Poll::Pending => Poll::Ready(Ok(true)),
+    })
+    .await?;
+    if idle {
+        writer.flush().await.map_err(BodyWriteFailed)?;
+    }
+    Ok(())
+}
+
+/// Relay a chunked body verbatim, including the last chunk and any trailers
+async fn relay_chunked<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0u64;
+    let mut line = Vec::new();
+    loop {
+        flush_if_idle(reader, writer).await?;
+        read_line(reader, &mut line).await?;
+        writer.write_all(&line).await.map_err(BodyWriteFailed)?;
+        total += line.len() as u64;
+
+        let size_str = String::from_utf8_lossy(&line);
+        let size_str = size_str.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size_str, 16)
+            .map_err(|_| anyhow!("Invalid chunk size: {}", size_str))?;
+
+        if size == 0 {
+            // Trailers are forwarded verbatim up to the empty line ending the body
+            let mut trailer_size = 0;
+            loop {
+                read_line(reader, &mut line).await?;
+                trailer_size += line.len() as u64;
+                if trailer_size > MAX_TRAILER_SIZE {
+                    return Err(anyhow!("Chunked body trailers exceed {} bytes", MAX_TRAILER_SIZE));
+                }
+                writer.write_all(&line).await.map_err(BodyWriteFailed)?;
+                total += line.len() as u64;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(total);
+                }
+            }
+        }
+
+        // Chunk data followed by its CRLF
+        let length = size.checked_add(2).ok_or_else(|| anyhow!("Chunk size too large: {}", size_str))?;
+        let copied = copy_flushing(&mut reader.take(size), writer).await?;
+        let mut crlf = [0; 2];
+        if copied < size || reader.read_exact(&mut crlf).await.is_err() {
+            return Err(anyhow!("Connection closed in the middle of a chunk"));
+        }
+        if crlf != *b"\r\n" {
+            return Err(anyhow!("Chunk data not followed by CRLF"));
+        }
+        writer.write_all(&crlf).await.map_err(BodyWriteFailed)?;
+        total += length;
+    }
+}
+
+/// Read a single line including its terminator, bounded in length
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> Result<()> {
+    line.clear();
+    reader.take(MAX_CHUNK_LINE).read_until(b'\n', line).await?;
+    if line.last() != Some(&b'\n') {
+        return Err(anyhow!("Malformed or truncated chunked body"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(headers: &str) -> String {
+        format!("POST http://example.test/ HTTP/1.1\r\nHost: example.test\r\n{}\r\n", headers)
+    }
+
+    #[test]
+    fn recognizes_http_request_starts() {
+        assert!(looks_like_http(b"GET / HTTP/1.1\r\n"));
+        assert!(looks_like_http(b"CONNECT example.test:443 HTTP/1.1\r\n"));
+        // Only part of the method arrived so far
+        assert!(looks_like_http(b"CONN"));
+        assert!(!looks_like_http(b"\x16\x03\x01\x02\x00"));
+        assert!(!looks_like_http(b"\x05\x01\x00"));
+        assert!(!looks_like_http(b" GET / HTTP/1.1\r\n"));
+        assert!(!looks_like_http(b"GET\x00/ HTTP/1.1\r\n"));
+        assert!(!looks_like_http(&[b'A'; 40]));
+    }
+
+    #[test]
+    fn classifies_request_targets() {
+        assert_eq!(RequestTarget::of("*"), RequestTarget::Asterisk);
+        assert_eq!(RequestTarget::of("/index.html?q=*"), RequestTarget::Origin);
+        assert_eq!(RequestTarget::of("http://example.test/"), RequestTarget::Absolute);
+        assert_eq!(RequestTarget::of("example.test:443"), RequestTarget::Authority);
+        assert_eq!(RequestTarget::of("[2001:db8::1]:443"), RequestTarget::Authority);
+    }
+
+    #[test]
+    fn finds_head_ends_with_either_line_ending() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\nbody"), Some(18));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\n\nbody"), Some(16));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\nHost: a\n\r\n"), Some(25));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+    }
+
+    #[test]
+    fn normalizes_bare_line_feeds() {
+        let normalized = normalize_line_endings(b"GET / HTTP/1.1\nHost: a\r\nAccept: */*\n\n".to_vec());
+        assert_eq!(normalized, b"GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n");
+        let canonical = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec();
+        assert_eq!(normalize_line_endings(canonical.clone()), canonical);
+    }
+
+    /// What the full rewrite makes of `head`, the reference for `forward_head`
+    fn fully_rewritten(head: &str, connection: &str, auth: Option<&str>) -> String {
+        let head = strip_hop_by_hop(head);
+        let head = set_header(&head, "Connection", connection);
+        let head = set_header(&head, "Proxy-Connection", connection);
+        match auth {
+            Some(auth) => set_header(&head, "Proxy-Authorization", auth),
+            None => remove_header(&head, "Proxy-Authorization"),
+        }
+    }
+
+    /// Request line and sorted header lines, to compare heads regardless of header order
+    fn normalized(head: &str) -> (String, Vec<String>) {
+        let mut lines = head.lines().filter(|line| !line.is_empty()).map(str::to_string);
+        let request_line = lines.next().unwrap();
+        let mut headers: Vec<_> = lines.collect();
+        headers.sort();
+        (request_line, headers)
+    }
+
+    /// Request heads covering the hop-by-hop and authorization cases
+    fn heads() -> Vec<String> {
+        [
+            "GET http://example.test/ HTTP/1.1\r\nHost: example.test\r\n\r\n",
+            "GET http://example.test/ HTTP/1.1\r\nHost: example.test\r\nConnection: close\r\nProxy-Connection: close\r\n\r\n",
+            "GET http://example.test/ HTTP/1.1\r\nHost: example.test\r\nConnection: X-Trace, Keep-Alive\r\nX-Trace: 1\r\nKeep-Alive: timeout=5\r\n\r\n",
+            "POST http://example.test/form HTTP/1.1\r\nHost: example.test\r\nProxy-Authorization: Basic Y2xpZW50OnB3\r\nContent-Length: 3\r\n\r\n",
+            "GET http://example.test/ HTTP/1.0\r\nHost: example.test\r\nUser-Agent: old\r\n\r\n",
+        ]
+        .iter()
+        .map(|head| head.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn forward_head_matches_the_full_rewrite() {
+        for head in heads() {
+            for connection in ["close", "keep-alive"] {
+                for auth in [None, Some("Basic dXBzdHJlYW06cHc=")] {
+                    let forwarded = forward_head(&head, connection, auth);
+                    let expected = fully_rewritten(&head, connection, auth);
+                    if forwarded == head {
+                        // Forwarded as is, the defaults of the HTTP version stand in for missing headers
+                        assert_eq!(header_value(&forwarded, "Proxy-Authorization"), auth, "{}", head);
+                        assert_eq!(header_values(&forwarded, "Keep-Alive").count(), 0, "{}", head);
+                    } else {
+                        assert_eq!(normalized(&forwarded), normalized(&expected), "{}", head);
+                    }
+                    assert!(forwarded.ends_with("\r\n\r\n"), "{:?}", forwarded);
+                }
+            }
+        }
+    }
+
+    /// Throughput of the single-pass rewrite against the full one
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture forward_head_throughput`.
+    #[test]
+    #[ignore]
+    fn forward_head_throughput() {
+        const ROUNDS: usize = 100_000;
+        let heads = heads();
+        let time = |rewrite: &dyn Fn(&str) -> String| {
+            let started = std::time::Instant::now();
+            for round in 0..ROUNDS {
+                std::hint::black_box(rewrite(std::hint::black_box(&heads[round % heads.len()])));
+            }
+            started.elapsed()
+        };
+        let auth = Some("Basic dXBzdHJlYW06cHc=");
+        let full = time(&|head| fully_rewritten(head, "keep-alive", auth));
+        let fast = time(&|head| forward_head(head, "keep-alive", auth));
+        let per_second = |elapsed: Duration| ROUNDS as f64 / elapsed.as_secs_f64();
+        println!("full rewrite: {:.0} heads/s, single pass: {:.0} heads/s", per_second(full), per_second(fast));
+        assert!(fast < full, "single pass took {:?}, full rewrite {:?}", fast, full);
+    }
+
+    #[test]
+    fn rewrites_absolute_targets_to_origin_form() {
+        let head = "GET http://example.test/a/b?c HTTP/1.1\r\nHost: other.test\r\nAccept: */*\r\n\r\n";
+        let rewritten = with_origin_form_target(head);
+        assert!(rewritten.starts_with("GET /a/b?c HTTP/1.1\r\n"), "{}", rewritten);
+        assert_eq!(header_values(&rewritten, "Host").collect::<Vec<_>>(), ["example.test"]);
+        assert_eq!(header_value(&rewritten, "Accept"), Some("*/*"));
+        // A URI without a path asks for the root
+        let rewritten = with_origin_form_target("GET http://example.test HTTP/1.1\r\n\r\n");
+        assert!(rewritten.starts_with("GET / HTTP/1.1\r\n"), "{}", rewritten);
+        let origin = "GET /already HTTP/1.1\r\nHost: example.test\r\n\r\n";
+        assert_eq!(with_origin_form_target(origin), origin);
+    }
+
+    #[test]
+    fn accepts_well_framed_requests() {
+        assert_eq!(validate_request_framing(&post("")), Ok(()));
+        assert_eq!(validate_request_framing(&post("Content-Length: 5\r\n")), Ok(()));
+        assert_eq!(validate_request_framing(&post("Content-Length: 5\r\nContent-Length: 5\r\n")), Ok(()));
+        assert_eq!(validate_request_framing(&post("Transfer-Encoding: gzip, chunked\r\n")), Ok(()));
+    }
+
+    #[test]
+    fn rejects_smuggling_patterns() {
+        let cases = [
+            ("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n", "both Content-Length and Transfer-Encoding"),
+            ("Content-Length: 5\r\nContent-Length: 6\r\n", "conflicting Content-Length headers"),
+            ("Content-Length: 5, 6\r\n", "conflicting Content-Length headers"),
+            ("Content-Length: -5\r\n", "invalid Content-Length"),
+            ("Content-Length: 0x5\r\n", "invalid Content-Length"),
+            ("Transfer-Encoding: chunked, gzip\r\n", "Transfer-Encoding does not end in chunked"),
+            ("Transfer-Encoding : chunked\r\n", "malformed header name"),
+            ("X-Folded: a\r\n b\r\n", "obsolete line folding"),
+            ("No colon here\r\n", "malformed header line"),
+        ];
+        for (headers, reason) in cases {
+            assert_eq!(validate_request_framing(&post(headers)), Err(reason), "{:?}", headers);
+        }
+    }
+
+    /// Writer recording what it is given and in how many writes
+    #[derive(Default)]
+    struct Recorder {
+        data: std::sync::Arc<parking_lot::Mutex<Vec<u8>>>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.data.lock().extend_from_slice(buf);
+            this.writes += 1;
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_sizes_that_overflow() {
+        let body = b"ffffffffffffffff\r\nabc\r\n0\r\n\r\n";
+        let mut relayed = Vec::new();
+        let e = relay_body(&mut &body[..], &mut relayed, BodyLength::Chunked).await.unwrap_err();
+        assert!(e.to_string().contains("Chunk size too large"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_data_not_followed_by_crlf() {
+        let body = b"3\r\nabcXY5\r\nhello\r\n0\r\n\r\n";
+        let mut relayed = Vec::new();
+        let e = relay_body(&mut &body[..], &mut relayed, BodyLength::Chunked).await.unwrap_err();
+        assert!(e.to_string().contains("not followed by CRLF"), "{}", e);
+        // Closing early is not mistaken for a malformed chunk
+        let e = relay_body(&mut &b"3\r\nabc"[..], &mut Vec::new(), BodyLength::Chunked).await.unwrap_err();
+        assert!(e.to_string().contains("in the middle of a chunk"), "{}", e);
+    }
+
+    /// Chunked body of 100 three byte chunks
+    fn small_chunks() -> Vec<u8> {
+        let mut body = b"3\r\nabc\r\n".repeat(100);
+        body.extend_from_slice(b"0\r\n\r\n");
+        body
+    }
+
+    #[tokio::test]
+    async fn coalescing_batches_small_chunks_into_few_writes() {
+        let body = small_chunks();
+
+        let mut direct = Recorder::default();
+        relay_body(&mut &body[..], &mut direct, BodyLength::Chunked).await.unwrap();
+
+        let mut coalesced = tokio::io::BufWriter::with_capacity(4096, Recorder::default());
+        relay_body(&mut &body[..], &mut coalesced, BodyLength::Chunked).await.unwrap();
+        let coalesced = coalesced.into_inner();
+
+        assert_eq!(*direct.data.lock(), body);
+        assert_eq!(*coalesced.data.lock(), body);
+        // A chunk-size line and a chunk per write without coalescing, everything at once with it
+        assert!(direct.writes >= 200, "{} writes", direct.writes);
+        assert_eq!(coalesced.writes, 1);
+    }
+
+    #[tokio::test]
+    async fn coalescing_sends_what_it_has_while_the_upstream_is_idle() {
+        let (mut upstream, relayed) = tokio::io::duplex(1024);
+        let recorder = Recorder::default();
+        let data = recorder.data.clone();
+        let relay = tokio::spawn(async move {
+            let mut reader = tokio::io::BufReader::new(relayed);
+            let mut coalesced = tokio::io::BufWriter::with_capacity(4096, recorder);
+            relay_body(&mut reader, &mut coalesced, BodyLength::Chunked).await
+        });
+
+        upstream.write_all(b"5\r\nhello\r\n").await.unwrap();
+        // Nothing more comes for now, so the first chunk isn't held back
+        crate::testing::eventually(|| *data.lock() == b"5\r\nhello\r\n").await;
+        upstream.write_all(b"0\r\n\r\n").await.unwrap();
+        assert_eq!(relay.await.unwrap().unwrap(), 15);
+        assert_eq!(*data.lock(), b"5\r\nhello\r\n0\r\n\r\n");
+    }
+}
@@ -0,0 +1,319 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use hyper::{Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tracing::{debug, info, instrument, warn};
+
+use crate::observer::ConnectionOutcome;
+use crate::stats::{HostTransfer, LiveConnection};
+use crate::throttle::{ConnectionThrottle, pace_all};
+use crate::{
+    CLIENT_READ_TIMEOUT, ERROR_REASON_HEADER, ErrorReason, ProxyState, host_from_authority, normalize_connect_target,
+    open_tunnel, retry_after_secs,
+};
+
+/// Start of the connection preface of a client speaking HTTP/2 with prior knowledge
+pub(crate) const PREFACE_START: &str = "PRI * HTTP/2.0";
+
+/// Streams a client may have open at once on one connection
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Size of the buffer used for reading from the upstream of each tunnel
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Serve an HTTP/2 connection, opening a tunnel for each CONNECT stream
+///
+/// Plain (RFC 9113) and extended (RFC 8441) CONNECT requests are both
+/// tunnelled to their `:authority`, the same way as CONNECT over HTTP/1.1;
+/// each stream is relayed on its own task so tunnels don't hold up each other.
+/// Other requests are refused. Returns once the client has closed the
+/// connection and every tunnel on it is done.
+pub(crate) async fn serve<T>(
+    io: T,
+    state: &Arc<ProxyState>,
+    live: &Arc<LiveConnection>,
+    throttle: &ConnectionThrottle,
+) -> Result<ConnectionOutcome>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut builder = h2::server::Builder::new();
+    builder.max_concurrent_streams(MAX_CONCURRENT_STREAMS).enable_connect_protocol();
+    let mut connection = tokio::time::timeout(CLIENT_READ_TIMEOUT, builder.handshake::<_, Bytes>(io))
+        .await
+        .map_err(|_| anyhow!("Timeout waiting for the HTTP/2 handshake"))??;
+
+    let mut streams = JoinSet::new();
+    let (mut bytes_in, mut bytes_out) = (0, 0);
+    let mut shutdown = state.shutdown.subscribe();
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_for(|&stopping| stopping), if !draining => {
+                debug!("Asking HTTP/2 client to stop opening streams for shutdown");
+                connection.graceful_shutdown();
+                draining = true;
+            }
+            Some(Ok((up, down))) = streams.join_next() => {
+                bytes_in += up;
+                bytes_out += down;
+            }
+            accepted = connection.accept() => match accepted {
+                Some(Ok((request, respond))) => {
+                    streams.spawn(serve_stream(request, respond, state.clone(), live.clone(), throttle.clone()));
+                }
+                Some(Err(e)) => {
+                    debug!("HTTP/2 connection failed: {}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+    while let Some(finished) = streams.join_next().await {
+        if let Ok((up, down)) = finished {
+            bytes_in += up;
+            bytes_out += down;
+        }
+    }
+    Ok(ConnectionOutcome::Completed { bytes_in, bytes_out })
+}
+
+/// Tunnel a single stream, returning the bytes sent by the client and by the upstream
+#[instrument(
+    skip_all,
+    fields(stream = u32::from(respond.stream_id()), connect_handshake_ms = tracing::field::Empty)
+)]
+async fn serve_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    state: Arc<ProxyState>,
+    live: Arc<LiveConnection>,
+    throttle: ConnectionThrottle,
+) -> (u64, u64) {
+    let config = &state.config;
+    if request.method() != Method::CONNECT {
+        info!(method = %request.method(), "Rejecting HTTP/2 request that isn't a CONNECT");
+        refuse(&mut respond, &state, StatusCode::METHOD_NOT_ALLOWED, ErrorReason::InvalidRequest, None);
+        return (0, 0);
+    }
+    let target = request.uri().authority().map(|authority| authority.as_str()).unwrap_or_default();
+    let addr = match normalize_connect_target(target, config.default_connect_port) {
+        Ok(addr) => addr,
+        Err(reason) => {
+            info!(target_addr = %target, "Rejecting HTTP/2 CONNECT request: {}", reason);
+            refuse(&mut respond, &state, StatusCode::BAD_REQUEST, ErrorReason::InvalidTarget, None);
+            return (0, 0);
+        }
+    };
+    info!(target_addr = %addr, "HTTP/2 CONNECT request");
+    live.set_target(&addr);
+
+    if let Some(refill) = state.egress_exhausted() {
+        info!(target_addr = %addr, "Rejecting HTTP/2 CONNECT request: egress budget exhausted");
+        let reason = ErrorReason::EgressExhausted;
+        refuse(&mut respond, &state, StatusCode::SERVICE_UNAVAILABLE, reason, Some(retry_after_secs(refill)));
+        return (0, 0);
+    }
+
+    let client_auth = request.headers().get("proxy-authorization").and_then(|value| value.to_str().ok());
+    let (mut upstream, rest) = match open_tunnel(None, &addr, client_auth, &state, &live).await {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            info!(target_addr = %addr, "Failed to open tunnel for HTTP/2 stream: {}", e);
+            let (status, reason) = match ConnectionOutcome::from(&e) {
+                ConnectionOutcome::Denied => (StatusCode::SERVICE_UNAVAILABLE, ErrorReason::UpstreamSaturated),
+                _ => (StatusCode::BAD_GATEWAY, ErrorReason::for_connect_failure(&e)),
+            };
+            refuse(&mut respond, &state, status, reason, None);
+            return (0, 0);
+        }
+    };
+    if let Ok(upstream_addr) = upstream.peer_addr() {
+        live.set_upstream_addr(upstream_addr);
+    }
+
+    let mut send = match respond.send_response(Response::new(()), false) {
+        Ok(send) => send,
+        Err(e) => {
+            info!("Client went away before the tunnel to {} was established: {}", addr, e);
+            return (0, 0);
+        }
+    };
+    let body = request.into_body();
+    // Held until the tunnel closes
+    let _reservation = match &state.relay_memory {
+        Some(memory) => memory.reserve(RELAY_BUFFER_SIZE).await,
+        None => None,
+    };
+    // Counted apart from the connection's counters, which its other streams share
+    let relayed = (AtomicU64::new(0), AtomicU64::new(0));
+    let _transfer = HostTransfer::new(&state.stats, host_from_authority(&addr), || {
+        (relayed.0.load(Ordering::Relaxed), relayed.1.load(Ordering::Relaxed))
+    });
+    match relay(body, &mut send, &mut upstream, rest, &throttle, &live, &relayed).await {
+        Ok((client_bytes, upstream_bytes)) => {
+            info!("HTTP/2 tunnel closed. Client sent {} bytes, upstream sent {} bytes", client_bytes, upstream_bytes);
+            state.stats.record_tunnel_sizes(client_bytes, upstream_bytes);
+            (client_bytes, upstream_bytes)
+        }
+        Err(e) => {
+            info!("HTTP/2 tunnel to {} failed: {}", addr, e);
+            send.send_reset(h2::Reason::CONNECT_ERROR);
+            (relayed.0.load(Ordering::Relaxed), relayed.1.load(Ordering::Relaxed))
+        }
+    }
+}
+
+/// Answer a stream with an error status, ending it
+fn refuse(
+    respond: &mut SendResponse<Bytes>,
+    state: &ProxyState,
+    status: StatusCode,
+    reason: ErrorReason,
+    retry_after: Option<u64>,
+) {
+    let mut response = Response::builder().status(status);
+    if state.config.expose_error_reason {
+        response = response.header(ERROR_REASON_HEADER, reason.label());
+    }
+    if let Some(retry_after) = retry_after {
+        response = response.header("retry-after", retry_after);
+    }
+    let sent = response
+        .body(())
+        .map_err(anyhow::Error::from)
+        .and_then(|response| Ok(respond.send_response(response, true)?));
+    if let Err(e) = sent {
+        warn!("Failed to refuse HTTP/2 stream: {}", e);
+    }
+}
+
+/// Relay a stream and its upstream until both sides are done
+///
+/// The end of the client's stream shuts down the write half of the upstream
+/// and the upstream closing ends the stream, so half-closes propagate. Bytes
+/// are counted in `relayed` as they go, so they are known if relaying fails.
+async fn relay(
+    mut body: RecvStream,
+    send: &mut SendStream<Bytes>,
+    upstream: &mut TcpStream,
+    rest: Vec<u8>,
+    throttle: &ConnectionThrottle,
+    live: &LiveConnection,
+    relayed: &(AtomicU64, AtomicU64),
+) -> Result<(u64, u64)> {
+    let (mut reader, mut writer) = upstream.split();
+    let client_to_upstream = async {
+        let mut total = 0u64;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            writer.write_all(&chunk).await?;
+            total += chunk.len() as u64;
+            live.add_up(chunk.len() as u64);
+            relayed.0.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            pace_all(throttle.up(), chunk.len()).await;
+        }
+        writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(total)
+    };
+    let upstream_to_client = async {
+        let mut total = rest.len() as u64;
+        live.add_down(total);
+        relayed.1.fetch_add(total, Ordering::Relaxed);
+        send_all(send, Bytes::from(rest)).await?;
+        let mut buf = vec![0; RELAY_BUFFER_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            send_all(send, Bytes::copy_from_slice(&buf[..n])).await?;
+            total += n as u64;
+            live.add_down(n as u64);
+            relayed.1.fetch_add(n as u64, Ordering::Relaxed);
+            pace_all(throttle.down(), n).await;
+        }
+        send.send_data(Bytes::new(), true)?;
+        Ok::<_, anyhow::Error>(total)
+    };
+    tokio::try_join!(client_to_upstream, upstream_to_client)
+}
+
+/// Send data on a stream as the client's flow control window allows
+async fn send_all(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| send.poll_capacity(cx))
+            .await
+            .ok_or_else(|| anyhow!("Stream was closed by the client"))??;
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Open a CONNECT stream to `target` on an HTTP/2 connection, returning its two halves
+    async fn connect_stream(client: &mut h2::client::SendRequest<Bytes>, target: &str) -> (SendStream<Bytes>, RecvStream) {
+        let request = Request::builder().method(Method::CONNECT).uri(target).body(()).unwrap();
+        let (response, send) = client.send_request(request, false).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        (send, response.into_body())
+    }
+
+    /// Send `data` on a stream through an echoing tunnel and wait for it to come back
+    async fn echo_stream(send: &mut SendStream<Bytes>, recv: &mut RecvStream, data: &'static [u8]) {
+        send.send_data(Bytes::from_static(data), false).unwrap();
+        let mut echoed = Vec::new();
+        while echoed.len() < data.len() {
+            let chunk = recv.data().await.unwrap().unwrap();
+            recv.flow_control().release_capacity(chunk.len()).unwrap();
+            echoed.extend_from_slice(&chunk);
+        }
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn multiplexes_tunnels_over_one_connection() {
+        let (upstream, mut heads) = tunnel_upstream().await;
+        let mut config = config(upstream);
+        config.http2 = true;
+        let (_proxy, addr) = start(config).await;
+
+        let (mut client, connection) = h2::client::handshake(TcpStream::connect(addr).await.unwrap()).await.unwrap();
+        tokio::spawn(connection);
+        let (mut first_send, mut first_recv) = connect_stream(&mut client, "first.test:443").await;
+        let (mut second_send, mut second_recv) = connect_stream(&mut client, "second.test:443").await;
+        let mut targets = [heads.recv().await.unwrap(), heads.recv().await.unwrap()];
+        targets.sort();
+        assert!(targets[0].starts_with("CONNECT first.test:443 HTTP/1.1\r\n"), "{}", targets[0]);
+        assert!(targets[1].starts_with("CONNECT second.test:443 HTTP/1.1\r\n"), "{}", targets[1]);
+
+        // Both stay open, taking turns
+        echo_stream(&mut second_send, &mut second_recv, b"to the second").await;
+        echo_stream(&mut first_send, &mut first_recv, b"to the first").await;
+        echo_stream(&mut second_send, &mut second_recv, b"second again").await;
+
+        // Ending one stream leaves the other working
+        first_send.send_data(Bytes::new(), true).unwrap();
+        // The upstream closing its side ends the stream, with an empty DATA frame
+        while let Some(chunk) = first_recv.data().await {
+            assert_eq!(chunk.unwrap(), "");
+        }
+        echo_stream(&mut second_send, &mut second_recv, b"still here").await;
+    }
+}
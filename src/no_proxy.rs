@@ -0,0 +1,156 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use anyhow::{Result, anyhow};
+
+/// Destinations that are connected to directly instead of through the upstream proxy
+///
+/// Follows the conventional `NO_PROXY` format: a comma-separated list of
+/// domains (matching the domain and its subdomains, a leading `.` or `*.` is
+/// ignored), IP addresses and CIDR ranges. A lone `*` matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct NoProxy {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    Any,
+    Domain(String),
+    Network { addr: IpAddr, prefix_len: u8 },
+}
+
+impl NoProxy {
+    /// Parse a comma-separated `NO_PROXY` list, skipping empty entries
+    pub fn parse(list: &str) -> Self {
+        let mut no_proxy = NoProxy::default();
+        no_proxy.extend(list);
+        no_proxy
+    }
+
+    /// Add the entries of another comma-separated `NO_PROXY` list
+    pub fn extend(&mut self, list: &str) {
+        let entries = list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Entry::parse);
+        for entry in entries {
+            if !self.entries.contains(&entry) {
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    /// Whether there are no entries, i.e. nothing bypasses the upstream proxy
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether connections to `host` should bypass the upstream proxy
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+        let host = host.to_ascii_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+        self.entries.iter().any(|entry| match entry {
+            Entry::Any => true,
+            Entry::Domain(domain) => {
+                host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }
+            Entry::Network { addr, prefix_len } => ip.is_some_and(|ip| in_network(ip, *addr, *prefix_len)),
+        })
+    }
+
+    /// PAC (proxy auto-config) conditions on `host` matching the entries, one per entry
+    ///
+    /// PAC has no standard IPv6 network test, so IPv6 ranges other than
+    /// single addresses are left out.
+    pub(crate) fn pac_conditions(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Any => Some("true".to_string()),
+                Entry::Domain(domain) => Some(format!("host == \"{0}\" || dnsDomainIs(host, \".{0}\")", domain)),
+                Entry::Network { addr: IpAddr::V4(addr), prefix_len } => {
+                    let mask = std::net::Ipv4Addr::from(u32::MAX.checked_shl(32 - u32::from(*prefix_len)).unwrap_or(0));
+                    Some(format!("/^[0-9.]+$/.test(host) && isInNet(host, \"{}\", \"{}\")", addr, mask))
+                }
+                Entry::Network { addr: IpAddr::V6(addr), prefix_len: 128 } => {
+                    Some(format!("host == \"{0}\" || host == \"[{0}]\"", addr))
+                }
+                Entry::Network { .. } => None,
+            })
+            .collect()
+    }
+}
+
+impl Entry {
+    fn parse(entry: &str) -> Self {
+        if entry == "*" {
+            return Entry::Any;
+        }
+        let entry = entry.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(addr) = entry.parse::<IpAddr>() {
+            let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+            return Entry::Network { addr, prefix_len };
+        }
+        if let Some((addr, prefix_len)) = entry.split_once('/') {
+            if let (Ok(addr), Ok(prefix_len)) = (addr.parse::<IpAddr>(), prefix_len.parse::<u8>()) {
+                let max_len = if addr.is_ipv4() { 32 } else { 128 };
+                return Entry::Network { addr, prefix_len: prefix_len.min(max_len) };
+            }
+        }
+        let domain = entry.trim_start_matches('*').trim_start_matches('.').trim_end_matches('.');
+        Entry::Domain(domain.to_ascii_lowercase())
+    }
+}
+
+/// An IP address or CIDR range, e.g. `10.0.0.0/8` or `::1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Whether `ip` lies within the network, IPv4-mapped IPv6 addresses count as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        in_network(ip.to_canonical(), self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(network: &str) -> Result<Self> {
+        let (addr, prefix_len) = match network.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (network.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow!("Invalid IP address in {}", network))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in {}", network))?,
+            None => max_len,
+        };
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+/// Whether `ip` lies within `network/prefix_len`
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
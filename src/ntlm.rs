@@ -0,0 +1,406 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::http;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// Largest challenge response head accepted from the upstream
+const MAX_CHALLENGE_HEAD_SIZE: usize = 16 * 1024;
+
+/// Seconds between the Windows epoch (1601) and the Unix epoch
+const WINDOWS_EPOCH_OFFSET_SECS: u64 = 11_644_473_600;
+
+/// The parts of a Type 2 (challenge) message needed to answer it
+#[derive(Debug, Clone)]
+pub(crate) struct Challenge {
+    server_challenge: [u8; 8],
+    flags: u32,
+    target_info: Vec<u8>,
+}
+
+/// Build the Type 1 (negotiate) message
+pub(crate) fn negotiate_message() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(SIGNATURE);
+    msg.extend_from_slice(&1u32.to_le_bytes());
+    msg.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation buffers
+    msg.extend_from_slice(&[0; 16]);
+    msg
+}
+
+impl Challenge {
+    /// Parse a Type 2 (challenge) message
+    pub(crate) fn parse(msg: &[u8]) -> Result<Self> {
+        if msg.len() < 32 || &msg[..8] != SIGNATURE {
+            return Err(anyhow!("Invalid NTLM challenge message"));
+        }
+        if read_u32(msg, 8)? != 2 {
+            return Err(anyhow!("Expected NTLM challenge (type 2) message"));
+        }
+
+        let flags = read_u32(msg, 20)?;
+        let mut server_challenge = [0; 8];
+        server_challenge.copy_from_slice(&msg[24..32]);
+
+        // Target info is only present when the server negotiated it
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 && msg.len() >= 48 {
+            read_buffer(msg, 40)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Challenge {
+            server_challenge,
+            flags,
+            target_info,
+        })
+    }
+}
+
+/// Build the Type 3 (authenticate) message answering `challenge` with an NTLMv2 response
+///
+/// A user of the form `DOMAIN\user` supplies the domain when `domain` is empty.
+pub(crate) fn authenticate_message(
+    challenge: &Challenge,
+    user: &str,
+    password: &str,
+    domain: &str,
+    workstation: &str,
+) -> Vec<u8> {
+    let (domain, user) = match user.split_once('\\') {
+        Some((user_domain, user)) if domain.is_empty() => (user_domain, user),
+        _ => (domain, user),
+    };
+
+    let client_challenge: [u8; 8] = rand::random();
+    let timestamp = windows_timestamp();
+
+    // NTLMv2 hash keyed on the NT hash of the password
+    let nt_hash = Md4::digest(utf16le(password));
+    let mut identity = utf16le(&user.to_uppercase());
+    identity.extend(utf16le(domain));
+    let v2_hash = hmac_md5(&nt_hash, &[&identity]);
+
+    // NTLMv2 client blob
+    let mut blob = Vec::with_capacity(32 + challenge.target_info.len());
+    blob.extend_from_slice(&[0x01, 0x01, 0, 0]);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let nt_proof = hmac_md5(&v2_hash, &[&challenge.server_challenge, &blob]);
+    let mut nt_response = nt_proof.to_vec();
+    nt_response.extend_from_slice(&blob);
+
+    let mut lm_response = hmac_md5(&v2_hash, &[&challenge.server_challenge, &client_challenge]).to_vec();
+    lm_response.extend_from_slice(&client_challenge);
+
+    let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+    let encode = |s: &str| if unicode { utf16le(s) } else { s.as_bytes().to_vec() };
+    let payloads = [
+        lm_response,
+        nt_response,
+        encode(domain),
+        encode(user),
+        encode(workstation),
+        Vec::new(),
+    ];
+
+    // Fixed header of six security buffers followed by the negotiated flags
+    let header_len = 8 + 4 + payloads.len() * 8 + 4;
+    let mut msg = Vec::with_capacity(header_len + payloads.iter().map(Vec::len).sum::<usize>());
+    msg.extend_from_slice(SIGNATURE);
+    msg.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = header_len;
+    for payload in &payloads {
+        msg.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += payload.len();
+    }
+    let flags = (challenge.flags & NEGOTIATE_FLAGS) | if unicode { NEGOTIATE_UNICODE } else { NEGOTIATE_OEM };
+    msg.extend_from_slice(&flags.to_le_bytes());
+    for payload in &payloads {
+        msg.extend_from_slice(payload);
+    }
+    msg
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Current time in 100ns intervals since 1601-01-01
+fn windows_timestamp() -> u64 {
+    let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_unix.as_secs() + WINDOWS_EPOCH_OFFSET_SECS) * 10_000_000 + u64::from(since_unix.subsec_nanos() / 100)
+}
+
+fn read_u32(msg: &[u8], offset: usize) -> Result<u32> {
+    let bytes = msg
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Truncated NTLM message"))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+/// Read the payload referenced by the security buffer at `offset`
+fn read_buffer(msg: &[u8], offset: usize) -> Result<&[u8]> {
+    let header = msg
+        .get(offset..offset + 8)
+        .ok_or_else(|| anyhow!("Truncated NTLM message"))?;
+    let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+    let start = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    msg.get(start..start + len)
+        .ok_or_else(|| anyhow!("NTLM security buffer out of bounds"))
+}
+
+/// Drive the negotiate/challenge legs of an NTLM handshake on a kept-alive upstream connection
+///
+/// `build_request` renders the request to send for a given `Proxy-Authorization`
+/// value. The request is sent with a Type 1 message and the upstream's `407`
+/// challenge is consumed. Returns the `Proxy-Authorization` value carrying the
+/// Type 3 message, to be sent with the final request on the same connection.
+pub(crate) async fn handshake<S, F>(
+    upstream: &mut S,
+    build_request: F,
+    user: &str,
+    password: &str,
+    domain: &str,
+    workstation: &str,
+) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&str) -> String,
+{
+    // The challenge must arrive on this connection, whatever the request asks for otherwise
+    let negotiate = format!("NTLM {}", BASE64.encode(negotiate_message()));
+    let request = http::set_header(&build_request(&negotiate), "Connection", "keep-alive");
+    let request = http::set_header(&request, "Proxy-Connection", "keep-alive");
+    upstream.write_all(request.as_bytes()).await?;
+    debug!("Sent NTLM negotiate message to upstream proxy");
+
+    let (head, rest) = http::read_head(upstream, MAX_CHALLENGE_HEAD_SIZE).await?;
+    let head = String::from_utf8_lossy(&head);
+    if http::status_code(&head) != Some(407) {
+        return Err(anyhow!("Expected NTLM challenge from upstream proxy, got: {}", head.lines().next().unwrap_or_default()));
+    }
+    if http::header_values(&head, "Connection")
+        .chain(http::header_values(&head, "Proxy-Connection"))
+        .any(|value| value.eq_ignore_ascii_case("close"))
+    {
+        return Err(anyhow!("Upstream proxy closed the connection during the NTLM handshake"));
+    }
+
+    let challenge = http::header_values(&head, "Proxy-Authenticate")
+        .find_map(|value| value.strip_prefix("NTLM "))
+        .ok_or_else(|| anyhow!("Upstream proxy did not send an NTLM challenge"))?;
+    let challenge = Challenge::parse(&BASE64.decode(challenge.trim())?)?;
+    http::discard_body(upstream, &head, rest.len()).await?;
+    debug!("Received NTLM challenge from upstream proxy");
+
+    let authenticate = authenticate_message(&challenge, user, password, domain, workstation);
+    Ok(format!("NTLM {}", BASE64.encode(authenticate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::ProxyAuth;
+    use crate::testing::*;
+
+    const SERVER_CHALLENGE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    /// A Type 2 message with `target_info` as its target information
+    fn challenge_message(target_info: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(SIGNATURE);
+        msg.extend_from_slice(&2u32.to_le_bytes());
+        // Empty target name
+        msg.extend_from_slice(&[0, 0, 0, 0, 48, 0, 0, 0]);
+        msg.extend_from_slice(&(NEGOTIATE_FLAGS & !NEGOTIATE_OEM).to_le_bytes());
+        msg.extend_from_slice(&SERVER_CHALLENGE);
+        msg.extend_from_slice(&[0; 8]);
+        let len = (target_info.len() as u16).to_le_bytes();
+        msg.extend_from_slice(&[len[0], len[1], len[0], len[1], 48, 0, 0, 0]);
+        msg.extend_from_slice(target_info);
+        msg
+    }
+
+    /// Check the structure of a Type 3 message, returning its domain, user, and workstation
+    fn parse_authenticate(msg: &[u8], password: &str) -> (String, String, String) {
+        assert_eq!(&msg[..8], SIGNATURE);
+        assert_eq!(read_u32(msg, 8).unwrap(), 3);
+        let text = |offset| {
+            let bytes = read_buffer(msg, offset).unwrap();
+            let units: Vec<u16> = bytes.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16(&units).unwrap()
+        };
+        let (domain, user, workstation) = (text(28), text(36), text(44));
+        assert_eq!(read_buffer(msg, 12).unwrap().len(), 24);
+
+        // The NTLMv2 proof must match the blob it was sent with
+        let nt_response = read_buffer(msg, 20).unwrap();
+        let (proof, blob) = nt_response.split_at(16);
+        assert_eq!(&blob[..4], &[1, 1, 0, 0]);
+        let mut identity = utf16le(&user.to_uppercase());
+        identity.extend(utf16le(&domain));
+        let v2_hash = hmac_md5(&Md4::digest(utf16le(password)), &[&identity]);
+        assert_eq!(proof, hmac_md5(&v2_hash, &[&SERVER_CHALLENGE, blob]));
+        (domain, user, workstation)
+    }
+
+    #[test]
+    fn negotiate_message_requests_ntlm() {
+        let msg = negotiate_message();
+        assert_eq!(&msg[..8], SIGNATURE);
+        assert_eq!(read_u32(&msg, 8).unwrap(), 1);
+        assert_ne!(read_u32(&msg, 12).unwrap() & NEGOTIATE_NTLM, 0);
+    }
+
+    #[test]
+    fn parses_challenge() {
+        let challenge = Challenge::parse(&challenge_message(b"info")).unwrap();
+        assert_eq!(challenge.server_challenge, SERVER_CHALLENGE);
+        assert_eq!(challenge.target_info, b"info");
+        assert!(Challenge::parse(&negotiate_message()).is_err());
+        assert!(Challenge::parse(b"NTLMSSP\0").is_err());
+    }
+
+    #[test]
+    fn authenticate_message_answers_challenge() {
+        let challenge = Challenge::parse(&challenge_message(&[2, 0, 4, 0, b'C', 0, b'O', 0])).unwrap();
+        let msg = authenticate_message(&challenge, "alice", "secret", "CORP", "WS1");
+        let (domain, user, workstation) = parse_authenticate(&msg, "secret");
+        assert_eq!((domain.as_str(), user.as_str(), workstation.as_str()), ("CORP", "alice", "WS1"));
+    }
+
+    #[test]
+    fn domain_can_come_from_user() {
+        let challenge = Challenge::parse(&challenge_message(&[])).unwrap();
+        let msg = authenticate_message(&challenge, "CORP\\alice", "secret", "", "");
+        let (domain, user, _) = parse_authenticate(&msg, "secret");
+        assert_eq!((domain.as_str(), user.as_str()), ("CORP", "alice"));
+    }
+
+    #[tokio::test]
+    async fn handshake_sends_negotiate_and_answers_challenge() {
+        let (mut proxy, mut mock) = tokio::io::duplex(64 * 1024);
+        let upstream = tokio::spawn(async move {
+            let (head, _) = http::read_head(&mut mock, 16 * 1024).await.unwrap();
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("CONNECT example.test:443 HTTP/1.1\r\n"));
+            assert_eq!(http::header_value(&head, "Proxy-Connection"), Some("keep-alive"));
+            let negotiate = http::header_value(&head, "Proxy-Authorization").unwrap();
+            let negotiate = BASE64.decode(negotiate.strip_prefix("NTLM ").unwrap()).unwrap();
+            assert_eq!(read_u32(&negotiate, 8).unwrap(), 1);
+
+            let challenge = BASE64.encode(challenge_message(&[]));
+            let response = format!(
+                "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: NTLM {}\r\nContent-Length: 6\r\n\r\ndenied",
+                challenge
+            );
+            mock.write_all(response.as_bytes()).await.unwrap();
+            mock
+        });
+
+        let build = |auth: &str| format!("CONNECT example.test:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", auth);
+        let auth = handshake(&mut proxy, build, "alice", "secret", "CORP", "WS1").await.unwrap();
+        let mut mock = upstream.await.unwrap();
+        let authenticate = BASE64.decode(auth.strip_prefix("NTLM ").unwrap()).unwrap();
+        parse_authenticate(&authenticate, "secret");
+
+        // The challenge's body was consumed, only what follows it is left
+        mock.write_all(b"!").await.unwrap();
+        let mut next = [0; 1];
+        proxy.read_exact(&mut next).await.unwrap();
+        assert_eq!(&next, b"!");
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_without_challenge() {
+        let (mut proxy, mut mock) = tokio::io::duplex(64 * 1024);
+        mock.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        let build = |auth: &str| format!("GET http://example.test/ HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n", auth);
+        assert!(handshake(&mut proxy, build, "alice", "secret", "", "").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_ntlm_proxy_on_one_connection() {
+        let (listener, upstream) = listener().await;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let negotiate = read_head(&mut stream).await;
+            assert!(negotiate.contains("Proxy-Authorization: NTLM "));
+            let challenge = BASE64.encode(challenge_message(&[]));
+            let response = format!(
+                "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: NTLM {}\r\nContent-Length: 0\r\n\r\n",
+                challenge
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            // The Type 3 message must come on the same connection
+            let head = read_head(&mut stream).await;
+            assert!(head.starts_with("CONNECT example.test:443 "));
+            let auth = http::header_value(&head, "Proxy-Authorization").unwrap();
+            let authenticate = BASE64.decode(auth.strip_prefix("NTLM ").unwrap()).unwrap();
+            let (domain, user, workstation) = parse_authenticate(&authenticate, "secret");
+            assert_eq!((domain.as_str(), user.as_str(), workstation.as_str()), ("CORP", "alice", "WS1"));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let mut config = config(upstream);
+        config.proxy_user = "alice".to_string();
+        config.proxy_password = "secret".to_string();
+        config.proxy_auth = ProxyAuth::Ntlm {
+            domain: "CORP".to_string(),
+            workstation: "WS1".to_string(),
+        };
+        let (_proxy, addr) = start(config).await;
+        let mut tunnel = connect_tunnel(addr, "example.test:443").await;
+        echo(&mut tunnel, b"authenticated").await;
+    }
+}
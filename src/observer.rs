@@ -0,0 +1,112 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Hooks notified about notable events in the life of client connections
+///
+/// Every method has an empty default implementation, so implementors only
+/// override the events they care about. Methods are called from connection
+/// tasks and should return quickly.
+pub trait ProxyObserver: Send + Sync {
+    /// A connection handler panicked, the connection has been closed
+    fn on_panic(&self, _conn_id: &str, _peer: SocketAddr, _message: &str) {}
+
+    /// A connection was closed, with what came of it
+    fn on_connection_closed(&self, _conn_id: &str, _peer: SocketAddr, _outcome: &ConnectionOutcome) {}
+
+    /// A plain HTTP request was answered with `status`, `duration` after its head was received
+    fn on_request_completed(&self, _conn_id: &str, _peer: SocketAddr, _status: u16, _duration: Duration) {}
+
+    /// A request mirrored to the shadow upstream was answered with `status`, `None` if the replay failed
+    fn on_shadow_completed(&self, _conn_id: &str, _peer: SocketAddr, _status: Option<u16>) {}
+}
+
+/// How a client connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionOutcome {
+    /// Every request or the tunnel was served
+    Completed {
+        /// Bytes received from the client and relayed onwards
+        bytes_in: u64,
+        /// Bytes sent back to the client
+        bytes_out: u64,
+    },
+    /// The client went away before it could be served
+    ClientDisconnected,
+    /// The upstream proxy or destination couldn't be reached or misbehaved
+    UpstreamError,
+    /// The upstream proxy rejected our credentials
+    AuthFailed,
+    /// The request was refused, e.g. as malformed or over a rate limit
+    Denied,
+    /// The client or upstream took too long
+    TimedOut,
+    /// The connection was closed because the proxy is shutting down
+    ShuttingDown,
+    /// The connection was terminated at runtime, e.g. by cutting off its destination
+    Terminated,
+}
+
+impl ConnectionOutcome {
+    /// Short name of the outcome, suitable as a log field or metric label
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionOutcome::Completed { .. } => "completed",
+            ConnectionOutcome::ClientDisconnected => "client_disconnected",
+            ConnectionOutcome::UpstreamError => "upstream_error",
+            ConnectionOutcome::AuthFailed => "auth_failed",
+            ConnectionOutcome::Denied => "denied",
+            ConnectionOutcome::TimedOut => "timed_out",
+            ConnectionOutcome::ShuttingDown => "shutting_down",
+            ConnectionOutcome::Terminated => "terminated",
+        }
+    }
+}
+
+/// An error that determines the outcome of the connection it ended
+#[derive(Debug)]
+pub(crate) struct OutcomeError {
+    pub outcome: ConnectionOutcome,
+    message: String,
+}
+
+impl OutcomeError {
+    pub(crate) fn new(outcome: ConnectionOutcome, message: impl Into<String>) -> Self {
+        OutcomeError {
+            outcome,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for OutcomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for OutcomeError {}
+
+impl From<&anyhow::Error> for ConnectionOutcome {
+    /// Classify an error that ended a connection
+    fn from(e: &anyhow::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<OutcomeError>() {
+            return e.outcome;
+        }
+        let timed_out = e.chain().any(|cause| {
+            cause.is::<tokio::time::error::Elapsed>()
+                || cause.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+        });
+        if timed_out {
+            ConnectionOutcome::TimedOut
+        } else {
+            ConnectionOutcome::UpstreamError
+        }
+    }
+}
+
+impl fmt::Debug for dyn ProxyObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProxyObserver")
+    }
+}
@@ -0,0 +1,25 @@
+use std::net::SocketAddr;
+
+use crate::NoProxy;
+
+/// Media type of PAC files
+pub(crate) const CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Whether a request path is one WPAD clients fetch the PAC file from
+pub(crate) fn is_pac_path(path: &str) -> bool {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    path == "/wpad.dat" || path == "/proxy.pac"
+}
+
+/// Render a PAC (proxy auto-config) file pointing clients at the proxy on `proxy_addr`
+///
+/// Destinations matching `no_proxy` are reached `DIRECT`, like the proxy itself
+/// would connect to them.
+pub(crate) fn render(proxy_addr: SocketAddr, no_proxy: &NoProxy) -> String {
+    let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+    for condition in no_proxy.pac_conditions() {
+        pac.push_str(&format!("    if ({}) return \"DIRECT\";\n", condition));
+    }
+    pac.push_str(&format!("    return \"PROXY {}\";\n}}\n", proxy_addr));
+    pac
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::upstream::UpstreamConnection;
+
+/// Idle upstream connections kept for reuse by later HTTP requests
+pub(crate) struct UpstreamPool {
+    idle: Mutex<Vec<IdleConnection>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    max_age: Option<Duration>,
+}
+
+struct IdleConnection {
+    stream: UpstreamConnection,
+    expires: Instant,
+}
+
+impl UpstreamPool {
+    pub(crate) fn new(max_idle: usize, idle_timeout: Duration, max_age: Option<Duration>) -> Self {
+        UpstreamPool {
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            idle_timeout,
+            max_age,
+        }
+    }
+
+    /// Take the most recently used idle connection that is still usable
+    pub(crate) fn take(&self) -> Option<UpstreamConnection> {
+        let mut idle = self.idle.lock();
+        while let Some(connection) = idle.pop() {
+            if connection.expires <= Instant::now() {
+                debug!("Discarding expired pooled upstream connection");
+                continue;
+            }
+            if !is_reusable(&connection.stream) {
+                debug!("Discarding pooled upstream connection closed by the upstream");
+                continue;
+            }
+            return Some(connection.stream);
+        }
+        None
+    }
+
+    /// Return a connection to the pool once its response has been fully read
+    ///
+    /// `keep_alive_timeout` is the upstream's hint of how long it keeps idle
+    /// connections open, which shortens the configured idle timeout. A
+    /// connection expires when it reaches the maximum age as well.
+    pub(crate) fn put(&self, stream: UpstreamConnection, keep_alive_timeout: Option<Duration>) {
+        let mut timeout = keep_alive_timeout.map_or(self.idle_timeout, |hint| hint.min(self.idle_timeout));
+        if let Some(max_age) = self.max_age {
+            let Some(remaining) = max_age.checked_sub(stream.age()).filter(|remaining| !remaining.is_zero()) else {
+                debug!("Closing upstream connection that reached its maximum age");
+                return;
+            };
+            timeout = timeout.min(remaining);
+        }
+        let mut idle = self.idle.lock();
+        idle.retain(|connection| connection.expires > Instant::now());
+        if idle.len() >= self.max_idle {
+            debug!("Upstream pool is full, closing connection");
+            return;
+        }
+        idle.push(IdleConnection {
+            stream,
+            expires: Instant::now() + timeout,
+        });
+    }
+}
+
+/// Whether an idle connection is still open and has nothing unexpected to read
+fn is_reusable(stream: &UpstreamConnection) -> bool {
+    let mut probe = [0; 1];
+    match stream.try_read(&mut probe) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
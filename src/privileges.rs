@@ -0,0 +1,279 @@
+use std::ffi::CString;
+use anyhow::{Result, anyhow};
+use tracing::info;
+
+/// Switch to an unprivileged user and group, for good
+///
+/// `user` and `group` are names or numeric ids. Without a group the user's
+/// primary group is used, without a user only the group is changed. Must run
+/// as root, after privileged ports are bound; supplementary groups are
+/// dropped and regaining root is checked to fail.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, gid))) => Some(gid),
+        (None, None) => None,
+    };
+    if let Some(gid) = gid {
+        // SAFETY: plain system calls with a pointer to a single valid gid
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(anyhow!("Failed to drop supplementary groups: {}", std::io::Error::last_os_error()));
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(anyhow!("Failed to switch to group {}: {}", gid, std::io::Error::last_os_error()));
+        }
+    }
+    if let Some((uid, _)) = user {
+        // SAFETY: plain system call, applied to every thread by libc
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(anyhow!("Failed to switch to user {}: {}", uid, std::io::Error::last_os_error()));
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow!("Privileges were not dropped, switching back to root succeeded"));
+        }
+    }
+    // SAFETY: these calls can't fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    info!(uid, gid, "Dropped privileges");
+    Ok(())
+}
+
+/// User id and primary group of a user name or numeric id
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|_| anyhow!("Invalid user name {:?}", user))?;
+    let mut buf = vec![0; 16 * 1024];
+    // SAFETY: passwd is plain data, filled in by getpwnam_r
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call and the buffer length is its own
+    let ret = unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if ret == 0 && !found.is_null() {
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+    // Numeric ids without a passwd entry keep their own id as the group
+    match user.parse::<libc::uid_t>() {
+        Ok(uid) => Ok((uid, uid)),
+        Err(_) => Err(anyhow!("Unknown user {}", user)),
+    }
+}
+
+/// Group id of a group name or numeric id
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group).map_err(|_| anyhow!("Invalid group name {:?}", group))?;
+    let mut buf = vec![0; 16 * 1024];
+    // SAFETY: group is plain data, filled in by getgrnam_r
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call and the buffer length is its own
+    let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if ret == 0 && !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+    group.parse().map_err(|_| anyhow!("Unknown group {}", group))
+}
+
+/// Only allow the system calls the proxy makes once it is running
+///
+/// Anything else, such as executing programs, fails with `EPERM` instead of
+/// killing the process. The filter applies to every thread and can't be
+/// lifted; it also sets `no_new_privs`.
+#[cfg(all(feature = "seccomp", any(target_os = "linux", target_os = "android")))]
+pub fn apply_seccomp_filter() -> Result<()> {
+    seccomp::apply()
+}
+
+#[cfg(all(feature = "seccomp", not(any(target_os = "linux", target_os = "android"))))]
+pub fn apply_seccomp_filter() -> Result<()> {
+    Err(anyhow!("Seccomp filtering is only supported on Linux"))
+}
+
+#[cfg(all(feature = "seccomp", any(target_os = "linux", target_os = "android")))]
+mod seccomp {
+    use std::mem::offset_of;
+    use anyhow::{Result, anyhow};
+    use tracing::info;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls of the runtime, sockets, name resolution and logging
+    const ALLOWED: &[libc::c_long] = &[
+        libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64,
+        libc::SYS_close, libc::SYS_openat, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+        libc::SYS_lseek, libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_dup, libc::SYS_dup3,
+        libc::SYS_faccessat, libc::SYS_readlinkat, libc::SYS_getdents64, libc::SYS_uname,
+        libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_mprotect, libc::SYS_madvise, libc::SYS_brk,
+        libc::SYS_futex, libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep, libc::SYS_clock_gettime, libc::SYS_gettimeofday, libc::SYS_getrandom,
+        libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+        libc::SYS_exit, libc::SYS_exit_group, libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list,
+        libc::SYS_rseq, libc::SYS_prctl, libc::SYS_prlimit64, libc::SYS_getpid, libc::SYS_gettid,
+        libc::SYS_tgkill, libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+        libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_eventfd2,
+        libc::SYS_pipe2, libc::SYS_ppoll, libc::SYS_pselect6,
+        libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_accept4, libc::SYS_bind,
+        libc::SYS_listen, libc::SYS_shutdown, libc::SYS_setsockopt, libc::SYS_getsockopt,
+        libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_sendto, libc::SYS_recvfrom,
+        libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_sendmmsg, libc::SYS_recvmmsg,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_stat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_access,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_arch_prctl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_accept,
+    ];
+
+    pub(super) fn apply() -> Result<()> {
+        let allow = libc::SECCOMP_RET_ALLOW;
+        let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+        let mut filter = vec![
+            load(offset_of!(libc::seccomp_data, arch)),
+            jump_if(AUDIT_ARCH, 1, 0),
+            ret(deny),
+            load(offset_of!(libc::seccomp_data, nr)),
+        ];
+        for &nr in ALLOWED {
+            filter.push(jump_if(nr as u32, 0, 1));
+            filter.push(ret(allow));
+        }
+        filter.push(ret(deny));
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+
+        // SAFETY: prctl with integer arguments only
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(anyhow!("Failed to set no_new_privs: {}", std::io::Error::last_os_error()));
+        }
+        // SAFETY: the program points at `filter`, which outlives the call; the kernel copies it
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        match ret {
+            0 => {
+                info!(syscalls = ALLOWED.len(), "Installed seccomp filter");
+                Ok(())
+            }
+            // With TSYNC a positive result is a thread that couldn't be synchronized
+            tid if tid > 0 => Err(anyhow!("Failed to apply seccomp filter to thread {}", tid)),
+            _ => Err(anyhow!("Failed to install seccomp filter: {}", std::io::Error::last_os_error())),
+        }
+    }
+
+    fn load(offset: usize) -> libc::sock_filter {
+        libc::sock_filter {
+            code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+            jt: 0,
+            jf: 0,
+            k: offset as u32,
+        }
+    }
+
+    fn jump_if(value: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            jt,
+            jf,
+            k: value,
+        }
+    }
+
+    fn ret(action: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: libc::BPF_RET as u16 | libc::BPF_K as u16,
+            jt: 0,
+            jf: 0,
+            k: action,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set in the copy of the test binary a test restricts itself in
+    const CHILD: &str = "FORWARD_PROXY_TEST_CHILD";
+
+    /// Whether this is the child running `test`, otherwise run it and check it passes
+    ///
+    /// Dropping privileges and seccomp filters apply to the whole process, and
+    /// would affect every test running alongside.
+    fn in_child(test: &str) -> bool {
+        if std::env::var_os(CHILD).is_some() {
+            return true;
+        }
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", test, "--nocapture", "--test-threads=1"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        false
+    }
+
+    #[test]
+    fn looks_up_users_and_groups() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("4242").unwrap(), (4242, 4242));
+        assert!(lookup_user("no-such-user-here").is_err());
+        assert_eq!(lookup_group("4242").unwrap(), 4242);
+        assert!(lookup_group("no-such-group-here").is_err());
+    }
+
+    #[test]
+    fn drops_to_the_user_after_binding() {
+        // SAFETY: can't fail
+        if unsafe { libc::geteuid() } != 0 {
+            // Switching users takes root
+            return;
+        }
+        if !in_child("privileges::tests::drops_to_the_user_after_binding") {
+            return;
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        drop_privileges(Some("65534"), Some("65533")).unwrap();
+        // SAFETY: can't fail
+        assert_eq!(unsafe { (libc::geteuid(), libc::getegid()) }, (65534, 65533));
+        // The socket bound as root keeps accepting
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        listener.accept().unwrap();
+        assert!(drop_privileges(Some("0"), None).is_err());
+    }
+
+    #[cfg(all(feature = "seccomp", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn seccomp_filter_denies_unlisted_system_calls() {
+        if !in_child("privileges::tests::seccomp_filter_denies_unlisted_system_calls") {
+            return;
+        }
+        apply_seccomp_filter().unwrap();
+        let dir = CString::new("/").unwrap();
+        // SAFETY: a valid C string
+        assert_eq!(unsafe { libc::chdir(dir.as_ptr()) }, -1);
+        assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+        // Sockets still work
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        listener.accept().unwrap();
+    }
+}
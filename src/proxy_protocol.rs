@@ -0,0 +1,132 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature starting a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, line ending included
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY protocol header a load balancer sends ahead of the client's data
+///
+/// Returns the client's source address, or `None` when the header conveys
+/// none (`UNKNOWN`, `LOCAL` or a non-IP family). Nothing past the header is
+/// consumed; connections starting without a header are refused.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(anyhow!("PROXY protocol header is too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(anyhow!("Connection doesn't start with a PROXY protocol header"))
+    }
+}
+
+/// Parse a version 1 header line, without its line ending
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| anyhow!("Invalid PROXY protocol header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source.parse().map_err(|_| anyhow!("Invalid source address in PROXY protocol header: {}", source))?;
+            let port = port.parse().map_err(|_| anyhow!("Invalid source port in PROXY protocol header: {}", port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("Invalid PROXY protocol header: {}", line)),
+    }
+}
+
+/// Read the rest of a version 2 header, following its signature
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    reader.read_exact(&mut fixed).await?;
+    let [version_command, family, length @ ..] = fixed;
+    let mut payload = vec![0; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut payload).await?;
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", version_command >> 4));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. the load balancer's own health checks
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(anyhow!("Unsupported PROXY protocol command {}", command)),
+    }
+    let port = |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        2 if payload.len() >= 36 => {
+            let octets: [u8; 16] = payload[..16].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port(32))))
+        }
+        1 | 2 => Err(anyhow!("Truncated addresses in PROXY protocol header")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Version 2 `PROXY` header from 192.0.2.7:5000 over TCP/IPv4
+    fn v2_header() -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 7, 198, 51, 100, 1]);
+        header.extend_from_slice(&5000u16.to_be_bytes());
+        header.extend_from_slice(&3128u16.to_be_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers_leaving_what_follows() {
+        let mut reader: &[u8] = b"PROXY TCP4 192.0.2.7 198.51.100.1 5000 3128\r\nGET / HTTP/1.1\r\n";
+        let source = read_header(&mut reader).await.unwrap();
+        assert_eq!(source, Some("192.0.2.7:5000".parse().unwrap()));
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+
+        let mut reader: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 5000 3128\r\n";
+        assert_eq!(read_header(&mut reader).await.unwrap(), Some("[2001:db8::7]:5000".parse().unwrap()));
+        let mut reader: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_leaving_what_follows() {
+        let mut data = v2_header();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut reader = data.as_slice();
+        assert_eq!(read_header(&mut reader).await.unwrap(), Some("192.0.2.7:5000".parse().unwrap()));
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+
+        // LOCAL, without addresses
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_without_a_valid_header() {
+        let mut reader: &[u8] = b"GET / HTTP/1.1\r\nHost: example.test\r\n\r\n";
+        assert!(read_header(&mut reader).await.is_err());
+        let mut reader: &[u8] = b"PROXY TCP4 not-an-ip 198.51.100.1 5000 3128\r\n";
+        assert!(read_header(&mut reader).await.is_err());
+        let mut truncated = v2_header();
+        truncated[15] = 4;
+        assert!(read_header(&mut truncated.as_slice()).await.is_err());
+    }
+}
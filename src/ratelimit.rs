@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::Quota;
+use governor::middleware::NoOpMiddleware;
+
+/// A sustained request rate with an allowed burst
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests allowed per second
+    pub per_second: NonZeroU32,
+    /// Requests allowed in a single burst
+    pub burst: NonZeroU32,
+}
+
+impl RateLimit {
+    /// Create a rate limit allowing `per_second` requests with the given burst
+    pub fn new(per_second: NonZeroU32, burst: NonZeroU32) -> Self {
+        RateLimit { per_second, burst }
+    }
+
+    fn quota(&self) -> Quota {
+        Quota::per_second(self.per_second).allow_burst(self.burst)
+    }
+}
+
+/// Limiter with a bucket per client address
+type KeyedLimiter<C> = governor::RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, C, NoOpMiddleware<<C as Clock>::Instant>>;
+
+/// Limiter with a single bucket
+type DirectLimiter<C> = governor::RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<<C as Clock>::Instant>>;
+
+/// Enforces the configured per-client and global request rates
+pub(crate) struct RateLimiter<C: Clock = DefaultClock> {
+    per_ip: Option<KeyedLimiter<C>>,
+    global: Option<DirectLimiter<C>>,
+}
+
+impl RateLimiter {
+    /// Build a limiter, returning `None` when no limits are configured
+    pub(crate) fn new(per_ip: Option<RateLimit>, global: Option<RateLimit>) -> Option<Self> {
+        Self::with_clock(per_ip, global, DefaultClock::default())
+    }
+}
+
+impl<C: Clock + Clone> RateLimiter<C> {
+    /// Build a limiter keeping time with `clock`
+    fn with_clock(per_ip: Option<RateLimit>, global: Option<RateLimit>, clock: C) -> Option<Self> {
+        if per_ip.is_none() && global.is_none() {
+            return None;
+        }
+        Some(RateLimiter {
+            per_ip: per_ip.map(|limit| KeyedLimiter::new(limit.quota(), Default::default(), clock.clone())),
+            global: global.map(|limit| DirectLimiter::new(limit.quota(), Default::default(), clock)),
+        })
+    }
+
+    /// Check whether a request from `ip` may proceed
+    ///
+    /// On rejection, returns how long the client should wait before retrying.
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        if let Some(per_ip) = &self.per_ip {
+            per_ip
+                .check_key(&ip)
+                .map_err(|not_until| not_until.wait_time_from(per_ip.clock().now()))?;
+        }
+        if let Some(global) = &self.global {
+            global
+                .check()
+                .map_err(|not_until| not_until.wait_time_from(global.clock().now()))?;
+        }
+        Ok(())
+    }
+
+    /// Forget per-client state that no longer affects any decision
+    pub(crate) fn prune(&self) {
+        if let Some(per_ip) = &self.per_ip {
+            per_ip.retain_recent();
+            per_ip.shrink_to_fit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use governor::clock::FakeRelativeClock;
+
+    fn limit(per_second: u32, burst: u32) -> RateLimit {
+        RateLimit::new(NonZeroU32::new(per_second).unwrap(), NonZeroU32::new(burst).unwrap())
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn no_limits_means_no_limiter() {
+        assert!(RateLimiter::new(None, None).is_none());
+    }
+
+    #[test]
+    fn rejects_per_ip_requests_beyond_the_burst_until_the_rate_allows() {
+        let clock = FakeRelativeClock::default();
+        let limiter = RateLimiter::with_clock(Some(limit(2, 3)), None, clock.clone()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip(1)), Ok(()));
+        }
+        assert_eq!(limiter.check(ip(1)), Err(Duration::from_millis(500)));
+        // Other clients have buckets of their own
+        assert_eq!(limiter.check(ip(2)), Ok(()));
+
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(limiter.check(ip(1)), Err(Duration::from_millis(300)));
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(limiter.check(ip(1)), Ok(()));
+        assert!(limiter.check(ip(1)).is_err());
+    }
+
+    #[test]
+    fn global_limit_applies_across_clients() {
+        let clock = FakeRelativeClock::default();
+        let limiter = RateLimiter::with_clock(None, Some(limit(10, 2)), clock.clone()).unwrap();
+        assert_eq!(limiter.check(ip(1)), Ok(()));
+        assert_eq!(limiter.check(ip(2)), Ok(()));
+        assert_eq!(limiter.check(ip(3)), Err(Duration::from_millis(100)));
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(limiter.check(ip(3)), Ok(()));
+    }
+
+    #[test]
+    fn sustained_rate_is_enforced_after_the_burst() {
+        let clock = FakeRelativeClock::default();
+        let limiter = RateLimiter::with_clock(Some(limit(5, 1)), None, clock.clone()).unwrap();
+        let mut allowed = 0;
+        // A request every 50ms for two seconds, at twice the allowed rate
+        for _ in 0..40 {
+            if limiter.check(ip(1)).is_ok() {
+                allowed += 1;
+            }
+            clock.advance(Duration::from_millis(50));
+        }
+        assert_eq!(allowed, 10);
+    }
+}
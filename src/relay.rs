@@ -0,0 +1,467 @@
+use std::fmt;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::Mutex;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::stats::LiveConnection;
+use crate::throttle::{ConnectionThrottle, TokenBucket, pace_all};
+
+/// Size of the buffer used for each direction of a tunnel
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Options controlling how a tunnel is relayed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelOptions {
+    /// Check peer liveness after the tunnel has been idle this long
+    pub probe_interval: Option<Duration>,
+    /// Bandwidth cap applied to the tunnel
+    pub throttle: ConnectionThrottle,
+    /// Active connection whose byte counts are updated as data flows
+    pub live: Option<Arc<LiveConnection>>,
+    /// Budget the tunnel's buffers are reserved from
+    pub memory: Option<Arc<RelayMemory>>,
+}
+
+/// End of a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Upstream,
+}
+
+impl Side {
+    fn other(self) -> Side {
+        match self {
+            Side::Client => Side::Upstream,
+            Side::Upstream => Side::Client,
+        }
+    }
+}
+
+/// Error that ended a tunnel, with the end whose socket failed
+#[derive(Debug)]
+pub(crate) struct TunnelError {
+    pub side: Side,
+    pub error: io::Error,
+}
+
+impl TunnelError {
+    fn new(side: Side, error: io::Error) -> Self {
+        TunnelError { side, error }
+    }
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.side {
+            Side::Client => write!(f, "Client connection failed: {}", self.error),
+            Side::Upstream => write!(f, "Upstream connection failed: {}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for TunnelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Ceiling on the memory of relay buffers, shared by every connection
+///
+/// Buffers are reserved before they are allocated and released when their
+/// tunnel closes; while the budget is used up new tunnels wait for one to
+/// close before relaying anything.
+#[derive(Debug)]
+pub(crate) struct RelayMemory {
+    budget: Semaphore,
+    limit: usize,
+}
+
+impl RelayMemory {
+    pub(crate) fn new(limit: usize) -> Self {
+        RelayMemory {
+            budget: Semaphore::new(limit),
+            limit,
+        }
+    }
+
+    /// Reserve `bytes` of the budget, waiting until enough of it is free
+    ///
+    /// A reservation larger than the whole budget takes all of it, instead of
+    /// waiting forever.
+    pub(crate) async fn reserve(&self, bytes: usize) -> Option<SemaphorePermit<'_>> {
+        let bytes = bytes.min(self.limit) as u32;
+        if let Ok(permit) = self.budget.try_acquire_many(bytes) {
+            return Some(permit);
+        }
+        debug!("Relay memory budget is used up, waiting for a tunnel to close");
+        self.budget.acquire_many(bytes).await.ok()
+    }
+}
+
+/// Tracks the last time any bytes flowed through a tunnel
+struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock()
+    }
+}
+
+/// Relay bytes in both directions until both sides have finished
+///
+/// When one side reaches EOF, the write half of the other side is shut down so
+/// the close propagates through the tunnel. Returns the number of bytes sent by
+/// the client and by the upstream respectively.
+pub(crate) async fn tunnel(
+    client: &TcpStream,
+    upstream: &TcpStream,
+    options: &TunnelOptions,
+) -> Result<(u64, u64), TunnelError> {
+    let activity = Activity::new();
+    let client_done = Mutex::new(false);
+    let upstream_done = Mutex::new(false);
+    // Both directions at once, so tunnels can't each hold half of what they need
+    let _reservation = match &options.memory {
+        Some(memory) => memory.reserve(2 * RELAY_BUFFER_SIZE).await,
+        None => None,
+    };
+
+    if let Some(interval) = options.probe_interval {
+        enable_keepalive(client, interval);
+        enable_keepalive(upstream, interval);
+    }
+
+    let relay = async {
+        tokio::try_join!(
+            async {
+                let count = |n| if let Some(live) = &options.live { live.add_up(n) };
+                let result = copy_direction(client, upstream, Side::Client, options.throttle.up(), &activity, count).await;
+                *client_done.lock() = true;
+                result
+            },
+            async {
+                let count = |n| if let Some(live) = &options.live { live.add_down(n) };
+                let result = copy_direction(upstream, client, Side::Upstream, options.throttle.down(), &activity, count).await;
+                *upstream_done.lock() = true;
+                result
+            },
+        )
+    };
+
+    let Some(interval) = options.probe_interval else {
+        return relay.await;
+    };
+
+    let probe = async {
+        loop {
+            tokio::time::sleep_until(activity.last() + interval).await;
+            if activity.last().elapsed() < interval {
+                // Bytes flowed while we slept, the tunnel isn't idle yet
+                continue;
+            }
+            if let Some(e) = peer_gone(client, *client_done.lock()) {
+                return TunnelError::new(Side::Client, io::Error::new(e.kind(), format!("peer is gone: {}", e)));
+            }
+            if let Some(e) = peer_gone(upstream, *upstream_done.lock()) {
+                return TunnelError::new(Side::Upstream, io::Error::new(e.kind(), format!("peer is gone: {}", e)));
+            }
+            debug!("Idle tunnel passed liveness probe");
+            activity.touch();
+        }
+    };
+
+    tokio::select! {
+        result = relay => result,
+        e = probe => {
+            warn!("Tearing down idle tunnel: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Copy bytes from one socket to another until EOF, then shut down the writer
+///
+/// `count` is called with the size of every chunk relayed. Errors reading are
+/// blamed on `from_side`, errors writing on the other side.
+async fn copy_direction(
+    from: &TcpStream,
+    to: &TcpStream,
+    from_side: Side,
+    throttle: &[Arc<TokenBucket>],
+    activity: &Activity,
+    count: impl Fn(u64),
+) -> Result<u64, TunnelError> {
+    let mut buf = vec![0; RELAY_BUFFER_SIZE];
+    let mut total = 0u64;
+    let to_side = from_side.other();
+
+    loop {
+        from.readable().await.map_err(|e| TunnelError::new(from_side, e))?;
+        let n = match from.try_read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(TunnelError::new(from_side, e)),
+        };
+        write_all(to, &buf[..n]).await.map_err(|e| TunnelError::new(to_side, e))?;
+        activity.touch();
+        total += n as u64;
+        count(n as u64);
+        pace_all(throttle, n).await;
+    }
+
+    // Propagate the close, the peer may already be gone
+    if let Err(e) = SockRef::from(to).shutdown(Shutdown::Write) {
+        if e.kind() != io::ErrorKind::NotConnected {
+            return Err(TunnelError::new(to_side, e));
+        }
+    }
+    Ok(total)
+}
+
+/// Write all of `data` to a socket shared with a concurrent reader
+async fn write_all(to: &TcpStream, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        to.writable().await?;
+        match to.try_write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Check whether the peer of an idle socket has gone away
+///
+/// Pending socket errors (e.g. a reset or a keepalive timeout) always count as
+/// gone. A zero-length peek meaning the peer closed is only considered when
+/// we're still expecting to read from that side.
+fn peer_gone(stream: &TcpStream, read_done: bool) -> Option<io::Error> {
+    match stream.take_error() {
+        Ok(Some(e)) | Err(e) => return Some(e),
+        Ok(None) => {}
+    }
+    if read_done {
+        return None;
+    }
+
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1];
+    match SockRef::from(stream).peek(&mut buf) {
+        Ok(0) => Some(io::ErrorKind::UnexpectedEof.into()),
+        Ok(_) => None,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => Some(e),
+    }
+}
+
+/// Have the kernel probe the peer so a silently dead one surfaces as a socket error
+fn enable_keepalive(stream: &TcpStream, interval: Duration) {
+    let keepalive = TcpKeepalive::new()
+        .with_time(interval)
+        .with_interval((interval / 3).max(Duration::from_secs(1)))
+        .with_retries(3);
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        debug!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Both ends of a loopback TCP connection
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connect, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Close `stream` with a reset, like a peer whose host has gone away
+    fn reset(stream: TcpStream) {
+        SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+        drop(stream);
+    }
+
+    fn probing(interval: Duration) -> TunnelOptions {
+        TunnelOptions {
+            probe_interval: Some(interval),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_live_peer_is_not_gone() {
+        let (ours, _theirs) = socket_pair().await;
+        assert!(peer_gone(&ours, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn reset_peer_is_gone() {
+        let (ours, theirs) = socket_pair().await;
+        reset(theirs);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The reset is pending, so it counts even when nothing more is expected from that side
+        assert!(peer_gone(&ours, true).is_some());
+    }
+
+    #[tokio::test]
+    async fn dead_client_tears_down_tunnel_and_frees_upstream() {
+        let (client, client_peer) = socket_pair().await;
+        let (upstream, mut upstream_peer) = socket_pair().await;
+        let relay = tokio::spawn(async move {
+            tunnel(&client_peer, &upstream, &probing(Duration::from_millis(100))).await
+        });
+
+        reset(client);
+        let result = tokio::time::timeout(Duration::from_secs(2), relay).await.unwrap().unwrap();
+        assert_eq!(result.unwrap_err().side, Side::Client);
+        // The upstream socket was released, its peer sees the close
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), upstream_peer.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn idle_but_alive_tunnel_stays_open() {
+        let (mut client, client_peer) = socket_pair().await;
+        let (upstream, mut upstream_peer) = socket_pair().await;
+        let relay = tokio::spawn(async move {
+            tunnel(&client_peer, &upstream, &probing(Duration::from_millis(50))).await
+        });
+
+        // Several probe intervals without traffic
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!relay.is_finished());
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        upstream_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.shutdown().await.unwrap();
+        upstream_peer.shutdown().await.unwrap();
+        let (up, down) = relay.await.unwrap().unwrap();
+        assert_eq!((up, down), (4, 0));
+    }
+
+    /// Relay a tunnel on its own task, reserving its buffers from `memory`
+    fn spawn_tunnel(
+        client_peer: TcpStream,
+        upstream: TcpStream,
+        memory: &Arc<RelayMemory>,
+    ) -> tokio::task::JoinHandle<Result<(u64, u64), TunnelError>> {
+        let options = TunnelOptions {
+            memory: Some(memory.clone()),
+            ..Default::default()
+        };
+        tokio::spawn(async move { tunnel(&client_peer, &upstream, &options).await })
+    }
+
+    #[tokio::test]
+    async fn tunnels_wait_for_relay_memory_to_be_released() {
+        // Room for the buffers of a single tunnel
+        let memory = Arc::new(RelayMemory::new(2 * RELAY_BUFFER_SIZE));
+        let (mut first, first_peer) = socket_pair().await;
+        let (first_upstream, mut first_upstream_peer) = socket_pair().await;
+        let first_relay = spawn_tunnel(first_peer, first_upstream, &memory);
+        let (mut second, second_peer) = socket_pair().await;
+        let (second_upstream, mut second_upstream_peer) = socket_pair().await;
+        let second_relay = spawn_tunnel(second_peer, second_upstream, &memory);
+
+        first.write_all(b"first").await.unwrap();
+        let mut buf = [0; 5];
+        first_upstream_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(memory.budget.available_permits(), 0);
+        // The second tunnel relays nothing while the first holds the budget
+        second.write_all(b"later").await.unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(100), second_upstream_peer.read_exact(&mut buf)).await;
+        assert!(waited.is_err());
+
+        drop(first);
+        drop(first_upstream_peer);
+        first_relay.await.unwrap().unwrap();
+        second_upstream_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"later");
+        drop(second);
+        drop(second_upstream_peer);
+        second_relay.await.unwrap().unwrap();
+        assert_eq!(memory.budget.available_permits(), 2 * RELAY_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn transfers_complete_under_a_tight_memory_budget() {
+        const TUNNELS: usize = 8;
+        const SIZE: usize = 256 * 1024;
+        // Room for two tunnels at a time
+        let limit = 4 * RELAY_BUFFER_SIZE;
+        let memory = Arc::new(RelayMemory::new(limit));
+        let relaying = Arc::new(AtomicUsize::new(0));
+        let busiest = Arc::new(AtomicUsize::new(0));
+        let mut transfers = Vec::new();
+        for _ in 0..TUNNELS {
+            let (mut client, client_peer) = socket_pair().await;
+            let (upstream, mut upstream_peer) = socket_pair().await;
+            let relay = spawn_tunnel(client_peer, upstream, &memory);
+            let (relaying, busiest) = (relaying.clone(), busiest.clone());
+            transfers.push(tokio::spawn(async move {
+                let sent = async {
+                    client.write_all(&[7; SIZE]).await.unwrap();
+                    client.shutdown().await.unwrap();
+                };
+                let received = async {
+                    let mut received = vec![0; SIZE];
+                    // Tunnels relaying between their first and last byte
+                    upstream_peer.read_exact(&mut received[..1]).await.unwrap();
+                    let now = relaying.fetch_add(1, Ordering::SeqCst) + 1;
+                    busiest.fetch_max(now, Ordering::SeqCst);
+                    upstream_peer.read_exact(&mut received[1..]).await.unwrap();
+                    relaying.fetch_sub(1, Ordering::SeqCst);
+                    upstream_peer.shutdown().await.unwrap();
+                    received
+                };
+                let ((), received) = tokio::join!(sent, received);
+                assert!(received.iter().all(|&byte| byte == 7));
+                relay.await.unwrap().unwrap()
+            }));
+        }
+        for transfer in transfers {
+            assert_eq!(transfer.await.unwrap(), (SIZE as u64, 0));
+        }
+        assert!(busiest.load(Ordering::SeqCst) <= 2);
+        assert_eq!(memory.budget.available_permits(), limit);
+    }
+
+    #[tokio::test]
+    async fn oversized_reservations_take_the_whole_budget() {
+        let memory = RelayMemory::new(1024);
+        let permit = memory.reserve(4096).await.unwrap();
+        assert_eq!(permit.num_permits(), 1024);
+        assert_eq!(memory.budget.available_permits(), 0);
+    }
+}
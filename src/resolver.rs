@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
+use parking_lot::Mutex;
+use tokio::net::lookup_host;
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::debug;
+
+/// Future returned by [`NameResolver::resolve`]
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves hostnames to the addresses the proxy connects to
+///
+/// Used for both the upstream proxy and destinations reached directly, so
+/// custom name resolution (split-horizon DNS, service discovery, static
+/// overrides) can be plugged in.
+pub trait NameResolver: Send + Sync {
+    /// Resolve `host` to the socket addresses to try for `port`, in order of preference
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+impl fmt::Debug for dyn NameResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NameResolver")
+    }
+}
+
+/// What to do when a pluggable backend, such as a custom resolver, fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PolicyFailureMode {
+    /// Carry on as if the backend wasn't configured
+    #[default]
+    FailOpen,
+    /// Refuse the connection
+    FailClosed,
+}
+
+/// Resolver backed by the operating system (`getaddrinfo`)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl NameResolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Resolved addresses kept for reuse, so connections don't each wait for the resolver
+///
+/// Resolvers don't report record TTLs, so answers are kept for `ttl` and
+/// failures for `negative_ttl`.
+#[derive(Debug)]
+pub(crate) struct DnsCache {
+    entries: Mutex<HashMap<(String, u16), CachedAnswer>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+#[derive(Debug)]
+struct CachedAnswer {
+    /// The addresses, or why resolving failed
+    addrs: Result<Vec<SocketAddr>, String>,
+    expires: Instant,
+}
+
+impl DnsCache {
+    pub(crate) fn new(ttl: Duration, negative_ttl: Duration) -> Self {
+        DnsCache {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            negative_ttl,
+        }
+    }
+
+    /// The cached answer for `host`, or the result of `lookup` which is cached in turn
+    pub(crate) async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        lookup: impl Future<Output = Result<Vec<SocketAddr>>>,
+    ) -> Result<Vec<SocketAddr>> {
+        let key = (host.to_ascii_lowercase(), port);
+        if let Some(answer) = self.entries.lock().get(&key).filter(|answer| answer.expires > Instant::now()) {
+            debug!("Using cached addresses of {}", host);
+            return answer.addrs.clone().map_err(|e| anyhow!("{} (cached)", e));
+        }
+        let result = lookup.await;
+        let (addrs, ttl) = match &result {
+            Ok(addrs) => (Ok(addrs.clone()), self.ttl),
+            Err(e) => (Err(e.to_string()), self.negative_ttl),
+        };
+        if !ttl.is_zero() {
+            let expires = Instant::now() + ttl;
+            self.entries.lock().insert(key, CachedAnswer { addrs, expires });
+        }
+        result
+    }
+
+    /// Forget the answer for `host`, e.g. after failing to connect to one of its addresses
+    pub(crate) fn invalidate(&self, host: &str, port: u16) {
+        self.entries.lock().remove(&(host.to_ascii_lowercase(), port));
+    }
+}
+
+/// Resolutions in flight, shared by concurrent lookups of the same name and limited in number
+///
+/// Connections resolving a name that is already being resolved wait for that
+/// answer instead of asking the resolver again.
+#[derive(Debug)]
+pub(crate) struct DnsLookups {
+    in_flight: Mutex<HashMap<(String, u16), Arc<Flight>>>,
+    permits: Option<Semaphore>,
+}
+
+/// A single resolution, with the addresses or why it failed once done
+type Flight = OnceCell<Result<Vec<SocketAddr>, String>>;
+
+impl DnsLookups {
+    pub(crate) fn new(max_concurrent: Option<usize>) -> Self {
+        DnsLookups {
+            in_flight: Mutex::new(HashMap::new()),
+            permits: max_concurrent.map(Semaphore::new),
+        }
+    }
+
+    /// The result of `lookup`, or of the lookup of `host` already in flight
+    pub(crate) async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        lookup: impl Future<Output = Result<Vec<SocketAddr>>>,
+    ) -> Result<Vec<SocketAddr>> {
+        let key = (host.to_ascii_lowercase(), port);
+        let flight = self.in_flight.lock().entry(key.clone()).or_default().clone();
+        let result = flight
+            .get_or_init(|| async {
+                // Never closed, so a permit is always granted eventually
+                let _permit = match &self.permits {
+                    Some(permits) => permits.acquire().await.ok(),
+                    None => None,
+                };
+                lookup.await.map_err(|e| e.to_string())
+            })
+            .await
+            .clone();
+        let mut in_flight = self.in_flight.lock();
+        if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &flight)) {
+            debug!("Resolved {}", host);
+            in_flight.remove(&key);
+        }
+        result.map_err(|e| anyhow!(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn system_resolver_resolves_ip_literals() {
+        let addrs = SystemResolver.resolve("127.0.0.1", 8080).await.unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn custom_resolver_maps_fake_upstream_name() {
+        let (upstream, _) = tunnel_upstream().await;
+        let mut config = config(upstream);
+        config.proxy_host = "squid.invalid".to_string();
+        config.resolver = StaticResolver::new(&["127.0.0.1"]);
+        let (_proxy, addr) = start(config).await;
+        let mut tunnel = connect_tunnel(addr, "example.test:443").await;
+        echo(&mut tunnel, b"resolved").await;
+    }
+
+    /// Proxy in front of `upstream.test`, resolved by `resolver` and cached for a minute
+    async fn caching(upstream: SocketAddr, resolver: Arc<StaticResolver>) -> (crate::ProxyHandle, SocketAddr) {
+        let mut config = config(upstream);
+        config.proxy_host = "upstream.test".to_string();
+        config.resolver = resolver;
+        config.resolver_failure_mode = PolicyFailureMode::FailClosed;
+        config.upstream_dns_cache_ttl = Some(Duration::from_secs(60));
+        config.upstream_dns_negative_ttl = Duration::from_secs(60);
+        start(config).await
+    }
+
+    /// Send a `CONNECT` the proxy can't forward and return its status line
+    async fn failed_connect(addr: SocketAddr) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"CONNECT example.test:443 HTTP/1.1\r\n\r\n").await.unwrap();
+        let head = read_head(&mut client).await;
+        head.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn repeated_connects_within_the_ttl_resolve_once() {
+        let (upstream, _) = tunnel_upstream().await;
+        let resolver = StaticResolver::new(&["127.0.0.1"]);
+        let (_proxy, addr) = caching(upstream, resolver.clone()).await;
+        for _ in 0..3 {
+            let mut tunnel = connect_tunnel(addr, "example.test:443").await;
+            echo(&mut tunnel, b"cached").await;
+        }
+        assert_eq!(resolver.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_resolutions_are_cached_for_the_negative_ttl() {
+        let (upstream, _) = tunnel_upstream().await;
+        let resolver = StaticResolver::new(&[]);
+        let (_proxy, addr) = caching(upstream, resolver.clone()).await;
+        for _ in 0..3 {
+            let status = failed_connect(addr).await;
+            assert!(status.starts_with("HTTP/1.1 5"), "{}", status);
+        }
+        assert_eq!(resolver.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_failures_drop_the_cached_addresses() {
+        // Nothing listens there any more
+        let (listener, upstream) = listener().await;
+        drop(listener);
+        let resolver = StaticResolver::new(&["127.0.0.1"]);
+        let (_proxy, addr) = caching(upstream, resolver.clone()).await;
+        for _ in 0..2 {
+            let status = failed_connect(addr).await;
+            assert!(status.starts_with("HTTP/1.1 5"), "{}", status);
+        }
+        assert_eq!(resolver.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_connects_share_one_resolution() {
+        let (upstream, _) = tunnel_upstream().await;
+        let resolver = StaticResolver::slow(&["127.0.0.1"], Duration::from_millis(200));
+        let mut config = config(upstream);
+        config.proxy_host = "upstream.test".to_string();
+        config.resolver = resolver.clone();
+        let (_proxy, addr) = start(config).await;
+        let tunnels: Vec<_> = (0..10)
+            .map(|_| tokio::spawn(async move { echo(&mut connect_tunnel(addr, "example.test:443").await, b"shared").await }))
+            .collect();
+        for tunnel in tunnels {
+            tunnel.await.unwrap();
+        }
+        assert_eq!(resolver.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn limits_resolutions_in_flight() {
+        let lookups = Arc::new(DnsLookups::new(Some(2)));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let mut resolutions = tokio::task::JoinSet::new();
+        for i in 0..6 {
+            let (lookups, in_flight, most) = (lookups.clone(), in_flight.clone(), most.clone());
+            resolutions.spawn(async move {
+                let lookup = async {
+                    most.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok(vec!["127.0.0.1:443".parse().unwrap()])
+                };
+                lookups.resolve(&format!("host{}.test", i), 443, lookup).await
+            });
+        }
+        while let Some(result) = resolutions.join_next().await {
+            result.unwrap().unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
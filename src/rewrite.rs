@@ -0,0 +1,98 @@
+use std::fmt;
+use anyhow::{Result, anyhow};
+
+use crate::stats::ConnectionInfo;
+
+/// Hook transforming HTTP requests before they are forwarded
+///
+/// Called for every plain HTTP request once the proxy has parsed it and
+/// applied its own configured headers. The head may be changed freely;
+/// hop-by-hop headers, `Connection` and the `Proxy-Authorization` sent to the
+/// upstream are applied afterwards, so a rewriter can't override them.
+/// Headers framing the body (`Content-Length`, `Transfer-Encoding`) must be
+/// left alone. Routing decisions such as `no_proxy` are made on the request
+/// the client sent.
+pub trait RequestRewriter: Send + Sync {
+    /// Transform the head of a request from `client`
+    fn rewrite(&self, head: &mut RequestHead, client: &ConnectionInfo);
+}
+
+impl fmt::Debug for dyn RequestRewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestRewriter")
+    }
+}
+
+/// Head of an HTTP request handed to a [`RequestRewriter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    /// Request target, e.g. `http://example.com/path`
+    pub uri: String,
+    pub version: String,
+    /// Header fields in the order they were received
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Value of the first header called `name`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replace every header called `name` with a single one, added at the end
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove every header called `name`
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let (method, uri, version) = (parts.next()?, parts.next()?, parts.next()?);
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Some(RequestHead {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        let mut head = format!("{} {} {}\r\n", self.method, self.uri, self.version);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+/// Run a request head through `rewriter`, returning the head to forward
+pub(crate) fn apply(rewriter: &dyn RequestRewriter, head: &str, client: &ConnectionInfo) -> Result<String> {
+    let mut parsed = RequestHead::parse(head).ok_or_else(|| anyhow!("Invalid request head"))?;
+    rewriter.rewrite(&mut parsed, client);
+    let invalid = |field: &str| field.is_empty() || field.contains(char::is_whitespace);
+    if invalid(&parsed.method) || invalid(&parsed.uri) || invalid(&parsed.version) {
+        return Err(anyhow!("Request rewriter produced an invalid request line"));
+    }
+    let header_injection = parsed.headers.iter().any(|(name, value)| {
+        invalid(name) || name.contains(':') || value.contains(['\r', '\n'])
+    });
+    if header_injection {
+        return Err(anyhow!("Request rewriter produced an invalid header"));
+    }
+    Ok(parsed.serialize())
+}
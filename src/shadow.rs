@@ -0,0 +1,173 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Semaphore, mpsc};
+use tracing::{debug, info};
+
+use crate::{ProxyConfig, UpstreamProxy, http, upstream, upstream_authorization};
+
+/// Requests waiting to be replayed before further ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// Requests replayed to the shadow upstream at once
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Longest a replay may take, from connecting to reading the response head
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request body kept for replaying, requests with larger bodies aren't mirrored
+pub(crate) const MAX_BODY: usize = 1024 * 1024;
+
+/// Mirrors copies of HTTP requests to a shadow upstream proxy
+///
+/// Requests are queued for a background task and replayed there, the client
+/// is never kept waiting. Responses from the shadow are only logged and
+/// reported to the observer; when the queue is full requests aren't mirrored.
+pub(crate) struct ShadowMirror {
+    sender: mpsc::Sender<ShadowRequest>,
+    methods: Vec<String>,
+}
+
+struct ShadowRequest {
+    conn_id: String,
+    peer: SocketAddr,
+    uri: String,
+    head: String,
+    body: Vec<u8>,
+}
+
+impl ShadowMirror {
+    /// Start replaying to `proxy`, until the mirror is dropped
+    pub(crate) fn start(proxy: UpstreamProxy, config: &ProxyConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let methods = config.shadow_methods.clone();
+        tokio::spawn(replay_queued(proxy, Arc::new(config.clone()), receiver));
+        ShadowMirror { sender, methods }
+    }
+
+    /// Whether requests with `method` are mirrored
+    pub(crate) fn mirrors(&self, method: &str) -> bool {
+        self.methods.iter().any(|mirrored| mirrored.eq_ignore_ascii_case(method))
+    }
+
+    /// Queue a copy of a request forwarded to the primary upstream
+    pub(crate) fn mirror(&self, conn_id: &str, peer: SocketAddr, uri: &str, head: &str, body: Vec<u8>) {
+        let request = ShadowRequest {
+            conn_id: conn_id.to_string(),
+            peer,
+            uri: uri.to_string(),
+            head: head.to_string(),
+            body,
+        };
+        if self.sender.try_send(request).is_err() {
+            debug!(uri = %uri, "Shadow queue is full, not mirroring request");
+        }
+    }
+}
+
+async fn replay_queued(proxy: UpstreamProxy, config: Arc<ProxyConfig>, mut receiver: mpsc::Receiver<ShadowRequest>) {
+    let proxy = Arc::new(proxy);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    while let Some(request) = receiver.recv().await {
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            return;
+        };
+        let proxy = proxy.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let status = match tokio::time::timeout(REPLAY_TIMEOUT, replay(&proxy, &config, &request)).await {
+                Ok(Ok(status)) => {
+                    info!(uri = %request.uri, status, "Shadow upstream answered mirrored request");
+                    Some(status)
+                }
+                Ok(Err(e)) => {
+                    info!(uri = %request.uri, "Failed to mirror request to shadow upstream: {}", e);
+                    None
+                }
+                Err(_) => {
+                    info!(uri = %request.uri, "Timeout mirroring request to shadow upstream");
+                    None
+                }
+            };
+            if let Some(observer) = &config.observer {
+                observer.on_shadow_completed(&request.conn_id, request.peer, status);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Send a request to the shadow upstream and return the status it answered with
+async fn replay(proxy: &UpstreamProxy, config: &ProxyConfig, request: &ShadowRequest) -> Result<u16> {
+    let mut stream = upstream::connect_direct(&proxy.host, proxy.port, config, None).await?;
+    let head = http::remove_header(&request.head, "Proxy-Authorization");
+    let head = http::set_header(&head, "Connection", "close");
+    let head = http::set_header(&head, "Proxy-Connection", "close");
+    let build_request = |auth: &str| http::set_header(&head, "Proxy-Authorization", auth);
+    let head = if proxy.user.is_empty() {
+        head.clone()
+    } else {
+        build_request(&upstream_authorization(&mut stream, proxy.credentials(), build_request).await?)
+    };
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&request.body).await?;
+
+    let (response, _) = http::read_head(&mut stream, config.response_head_limit()).await?;
+    let response = String::from_utf8_lossy(&response);
+    http::status_code(&response)
+        .ok_or_else(|| anyhow!("Invalid response: {}", response.lines().next().unwrap_or_default()))
+}
+
+/// Writer passing bytes through while keeping a copy of them, up to a limit
+pub(crate) struct Recording<W> {
+    inner: W,
+    copy: Vec<u8>,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl<W> Recording<W> {
+    pub(crate) fn new(inner: W, limit: usize) -> Self {
+        Recording {
+            inner,
+            copy: Vec::new(),
+            limit,
+            overflowed: false,
+        }
+    }
+
+    /// The bytes written, or `None` if there were more than the limit
+    pub(crate) fn into_copy(self) -> Option<Vec<u8>> {
+        (!self.overflowed).then_some(self.copy)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Recording<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if this.copy.len() + written > this.limit {
+            this.overflowed = true;
+            this.copy = Vec::new();
+        } else if !this.overflowed {
+            this.copy.extend_from_slice(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
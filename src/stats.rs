@@ -0,0 +1,512 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use parking_lot::Mutex;
+use tokio::task::AbortHandle;
+use tracing::info;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::throttle::TokenBucket;
+
+/// Label used for hosts seen after the tracking limit has been reached
+pub const OTHER_HOSTS_LABEL: &str = "_other";
+
+/// Cumulative bytes transferred for a single destination host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostTraffic {
+    /// Bytes sent from the client towards the destination
+    pub bytes_up: u64,
+    /// Bytes sent from the destination back to the client
+    pub bytes_down: u64,
+}
+
+/// A client connection currently being served
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Connection id, as used in logs
+    pub id: String,
+    /// Address of the client
+    pub client_addr: SocketAddr,
+    /// Destination of the current or last request or tunnel, once known
+    pub target: Option<String>,
+    /// Address of the upstream proxy or destination connected to for it
+    pub upstream_addr: Option<SocketAddr>,
+    /// Bytes relayed from the client so far
+    pub bytes_up: u64,
+    /// Bytes relayed back to the client so far
+    pub bytes_down: u64,
+    /// Time since the connection was accepted
+    pub age: Duration,
+}
+
+/// Live state of an active connection, updated by its handler
+#[derive(Debug)]
+pub(crate) struct LiveConnection {
+    id: String,
+    client_addr: Mutex<SocketAddr>,
+    started: Instant,
+    target: Mutex<Option<String>>,
+    upstream_addr: Mutex<Option<SocketAddr>>,
+    connect_handshake: Mutex<Option<Duration>>,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    abort: Mutex<Option<AbortHandle>>,
+}
+
+impl LiveConnection {
+    /// Id of the connection
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Address of the client
+    pub(crate) fn client_addr(&self) -> SocketAddr {
+        *self.client_addr.lock()
+    }
+
+    /// Record the address of the client behind a load balancer
+    pub(crate) fn set_client_addr(&self, addr: SocketAddr) {
+        *self.client_addr.lock() = addr;
+    }
+
+    /// Record the destination the connection is currently relaying to
+    pub(crate) fn set_target(&self, target: &str) {
+        *self.target.lock() = Some(target.to_string());
+    }
+
+    /// Record the address of the upstream proxy or destination the connection is relaying to
+    pub(crate) fn set_upstream_addr(&self, addr: SocketAddr) {
+        *self.upstream_addr.lock() = Some(addr);
+    }
+
+    /// Address of the upstream proxy or destination last connected to
+    pub(crate) fn upstream_addr(&self) -> Option<SocketAddr> {
+        *self.upstream_addr.lock()
+    }
+
+    /// Record how long the upstream took to answer the tunnel's `CONNECT`
+    pub(crate) fn set_connect_handshake(&self, duration: Duration) {
+        *self.connect_handshake.lock() = Some(duration);
+    }
+
+    /// How long the upstream took to answer the tunnel's `CONNECT`, if one was sent
+    pub(crate) fn connect_handshake(&self) -> Option<Duration> {
+        *self.connect_handshake.lock()
+    }
+
+    /// Count bytes relayed from the client
+    pub(crate) fn add_up(&self, bytes: u64) {
+        self.bytes_up.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes relayed back to the client
+    pub(crate) fn add_down(&self, bytes: u64) {
+        self.bytes_down.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes relayed from and back to the client so far
+    pub(crate) fn transferred(&self) -> (u64, u64) {
+        (self.bytes_up.load(Ordering::Relaxed), self.bytes_down.load(Ordering::Relaxed))
+    }
+
+    /// Record the handle used to abort the connection's handler task
+    pub(crate) fn set_abort_handle(&self, handle: AbortHandle) {
+        *self.abort.lock() = Some(handle);
+    }
+
+    /// Whether the connection's current or last destination is `host`
+    fn targets_host(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+        self.target.lock().as_deref().is_some_and(|target| {
+            [target, crate::host_from_authority(target)]
+                .iter()
+                .any(|candidate| candidate.trim_end_matches('.').eq_ignore_ascii_case(host))
+        })
+    }
+
+    pub(crate) fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id.clone(),
+            client_addr: self.client_addr(),
+            target: self.target.lock().clone(),
+            upstream_addr: self.upstream_addr(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            age: self.started.elapsed(),
+        }
+    }
+}
+
+/// Records what a tunnel relayed for its destination host once dropped
+///
+/// Dropping covers every way a tunnel ends: closing cleanly, failing, or its
+/// task being aborted by `kill_host`. `transferred` tells the bytes sent by
+/// the client and by the upstream at that point.
+pub(crate) struct HostTransfer<'a, F: Fn() -> (u64, u64)> {
+    stats: &'a ProxyStats,
+    host: &'a str,
+    transferred: F,
+}
+
+impl<'a, F: Fn() -> (u64, u64)> HostTransfer<'a, F> {
+    pub(crate) fn new(stats: &'a ProxyStats, host: &'a str, transferred: F) -> Self {
+        HostTransfer { stats, host, transferred }
+    }
+}
+
+impl<F: Fn() -> (u64, u64)> Drop for HostTransfer<'_, F> {
+    fn drop(&mut self) {
+        let (up, down) = (self.transferred)();
+        self.stats.record_transfer(self.host, up, down);
+    }
+}
+
+/// Runtime statistics collected by the proxy
+pub struct ProxyStats {
+    registry: Registry,
+    connections_total: IntCounter,
+    rate_limited_total: IntCounter,
+    handler_panics_total: IntCounter,
+    non_http_rejected_total: IntCounter,
+    client_immediate_disconnects_total: IntCounter,
+    limit_exceeded_total: IntCounterVec,
+    bytes_total: IntCounterVec,
+    request_body_bytes: Histogram,
+    response_body_bytes: Histogram,
+    tunnel_bytes: HistogramVec,
+    connect_handshake_seconds: Histogram,
+    upstream_connections: IntGauge,
+    host_bytes: Option<HostBytes>,
+    connections: Mutex<HashMap<String, Arc<LiveConnection>>>,
+    egress_budget: Mutex<Option<(IntGauge, Arc<TokenBucket>)>>,
+}
+
+/// Per-destination byte accounting, bounded to a fixed number of hosts
+struct HostBytes {
+    counter: IntCounterVec,
+    hosts: Mutex<HashSet<String>>,
+    max_hosts: usize,
+}
+
+impl ProxyStats {
+    /// Create a new set of statistics
+    ///
+    /// When `max_tracked_hosts` is `Some`, bytes are additionally aggregated per
+    /// destination host. Once that many hosts are tracked, traffic for any new
+    /// host is accounted under [`OTHER_HOSTS_LABEL`].
+    pub fn new(max_tracked_hosts: Option<usize>) -> Result<Self> {
+        let registry = Registry::new();
+
+        let connections_total = IntCounter::new(
+            "proxy_connections_total",
+            "Total number of accepted client connections",
+        )?;
+        registry.register(Box::new(connections_total.clone()))?;
+
+        let rate_limited_total = IntCounter::new(
+            "proxy_rate_limited_total",
+            "Total number of requests rejected by the rate limiter",
+        )?;
+        registry.register(Box::new(rate_limited_total.clone()))?;
+
+        let handler_panics_total = IntCounter::new(
+            "proxy_handler_panics_total",
+            "Total number of connection handlers that panicked",
+        )?;
+        registry.register(Box::new(handler_panics_total.clone()))?;
+
+        let non_http_rejected_total = IntCounter::new(
+            "proxy_non_http_rejected_total",
+            "Total number of connections turned away for not speaking HTTP",
+        )?;
+        registry.register(Box::new(non_http_rejected_total.clone()))?;
+
+        let client_immediate_disconnects_total = IntCounter::new(
+            "proxy_client_immediate_disconnects_total",
+            "Total number of client connections closed without sending anything, e.g. by health checks",
+        )?;
+        registry.register(Box::new(client_immediate_disconnects_total.clone()))?;
+
+        let limit_exceeded_total = IntCounterVec::new(
+            Opts::new("proxy_limit_exceeded_total", "Total number of requests or responses rejected by a size limit"),
+            &["limit"],
+        )?;
+        registry.register(Box::new(limit_exceeded_total.clone()))?;
+
+        let bytes_total = IntCounterVec::new(
+            Opts::new("proxy_bytes_total", "Total bytes relayed by direction"),
+            &["direction"],
+        )?;
+        registry.register(Box::new(bytes_total.clone()))?;
+
+        let request_body_bytes = Histogram::with_opts(
+            HistogramOpts::new("proxy_request_body_bytes", "Sizes of HTTP request bodies").buckets(size_buckets()),
+        )?;
+        registry.register(Box::new(request_body_bytes.clone()))?;
+
+        let response_body_bytes = Histogram::with_opts(
+            HistogramOpts::new("proxy_response_body_bytes", "Sizes of HTTP response bodies").buckets(size_buckets()),
+        )?;
+        registry.register(Box::new(response_body_bytes.clone()))?;
+
+        let tunnel_bytes = HistogramVec::new(
+            HistogramOpts::new("proxy_tunnel_bytes", "Bytes relayed per tunnel by direction").buckets(size_buckets()),
+            &["direction"],
+        )?;
+        registry.register(Box::new(tunnel_bytes.clone()))?;
+
+        let connect_handshake_seconds = Histogram::with_opts(HistogramOpts::new(
+            "proxy_connect_handshake_seconds",
+            "Time from sending a CONNECT to the upstream proxy to receiving its response",
+        ))?;
+        registry.register(Box::new(connect_handshake_seconds.clone()))?;
+
+        let upstream_connections = IntGauge::new(
+            "proxy_upstream_connections",
+            "Number of connections to the upstream proxy currently open, idle pooled ones included",
+        )?;
+        registry.register(Box::new(upstream_connections.clone()))?;
+
+        let host_bytes = match max_tracked_hosts {
+            Some(max_hosts) => {
+                let counter = IntCounterVec::new(
+                    Opts::new("proxy_host_bytes_total", "Total bytes relayed by destination host and direction"),
+                    &["host", "direction"],
+                )?;
+                registry.register(Box::new(counter.clone()))?;
+                Some(HostBytes {
+                    counter,
+                    hosts: Mutex::new(HashSet::new()),
+                    max_hosts,
+                })
+            }
+            None => None,
+        };
+
+        Ok(ProxyStats {
+            registry,
+            connections_total,
+            rate_limited_total,
+            handler_panics_total,
+            non_http_rejected_total,
+            client_immediate_disconnects_total,
+            limit_exceeded_total,
+            bytes_total,
+            request_body_bytes,
+            response_body_bytes,
+            tunnel_bytes,
+            connect_handshake_seconds,
+            upstream_connections,
+            host_bytes,
+            connections: Mutex::new(HashMap::new()),
+            egress_budget: Mutex::new(None),
+        })
+    }
+
+    /// Record a newly accepted client connection
+    pub fn record_connection(&self) {
+        self.connections_total.inc();
+    }
+
+    /// Record a request rejected by the rate limiter
+    pub fn record_rate_limited(&self) {
+        self.rate_limited_total.inc();
+    }
+
+    /// Record a connection handler that panicked
+    pub fn record_panic(&self) {
+        self.handler_panics_total.inc();
+    }
+
+    /// Record a connection turned away for not speaking HTTP
+    pub fn record_non_http(&self) {
+        self.non_http_rejected_total.inc();
+    }
+
+    /// Record a client connection closed before sending anything
+    pub fn record_immediate_disconnect(&self) {
+        self.client_immediate_disconnects_total.inc();
+    }
+
+    /// Record a request or response rejected by the named size limit
+    pub fn record_limit_exceeded(&self, limit: &str) {
+        self.limit_exceeded_total.with_label_values(&[limit]).inc();
+    }
+
+    /// Record the body sizes of a completed HTTP exchange
+    pub fn record_http_sizes(&self, request_body: u64, response_body: u64) {
+        self.request_body_bytes.observe(request_body as f64);
+        self.response_body_bytes.observe(response_body as f64);
+    }
+
+    /// Record the bytes relayed each way by a closed tunnel
+    pub fn record_tunnel_sizes(&self, bytes_up: u64, bytes_down: u64) {
+        self.tunnel_bytes.with_label_values(&["up"]).observe(bytes_up as f64);
+        self.tunnel_bytes.with_label_values(&["down"]).observe(bytes_down as f64);
+    }
+
+    /// Record how long the upstream proxy took to answer a CONNECT
+    pub fn record_connect_handshake(&self, duration: Duration) {
+        self.connect_handshake_seconds.observe(duration.as_secs_f64());
+    }
+
+    /// Gauge of open upstream connections, raised and lowered by each connection as it opens and closes
+    pub(crate) fn upstream_connections_gauge(&self) -> IntGauge {
+        self.upstream_connections.clone()
+    }
+
+    /// Number of connections to the upstream proxy currently open
+    pub fn upstream_connections(&self) -> i64 {
+        self.upstream_connections.get()
+    }
+
+    /// Report the remaining bytes of an egress budget as a gauge
+    pub(crate) fn track_egress_budget(&self, budget: Arc<TokenBucket>) -> Result<()> {
+        let gauge = IntGauge::new(
+            "proxy_egress_budget_remaining_bytes",
+            "Bytes left of the egress budget for the current period",
+        )?;
+        self.registry.register(Box::new(gauge.clone()))?;
+        *self.egress_budget.lock() = Some((gauge, budget));
+        Ok(())
+    }
+
+    /// Bytes left of the egress budget, `None` when there is no budget
+    pub fn egress_budget_remaining(&self) -> Option<u64> {
+        self.egress_budget.lock().as_ref().map(|(_, budget)| budget.available().max(0.0) as u64)
+    }
+
+    /// Record bytes relayed on behalf of a client for the given destination host
+    pub fn record_transfer(&self, host: &str, bytes_up: u64, bytes_down: u64) {
+        self.bytes_total.with_label_values(&["up"]).inc_by(bytes_up);
+        self.bytes_total.with_label_values(&["down"]).inc_by(bytes_down);
+
+        if let Some(host_bytes) = &self.host_bytes {
+            let label = host_bytes.label_for(host);
+            host_bytes.counter.with_label_values(&[&label, "up"]).inc_by(bytes_up);
+            host_bytes.counter.with_label_values(&[&label, "down"]).inc_by(bytes_down);
+        }
+    }
+
+    /// Cumulative traffic for a single destination host, if per-host tracking is enabled
+    pub fn host_traffic(&self, host: &str) -> Option<HostTraffic> {
+        let host_bytes = self.host_bytes.as_ref()?;
+        let host = host.to_ascii_lowercase();
+        if !host_bytes.hosts.lock().contains(&host) {
+            return None;
+        }
+        Some(host_bytes.traffic(&host))
+    }
+
+    /// Cumulative traffic for every tracked destination host
+    pub fn host_traffic_snapshot(&self) -> HashMap<String, HostTraffic> {
+        let Some(host_bytes) = &self.host_bytes else {
+            return HashMap::new();
+        };
+        let hosts: Vec<String> = host_bytes.hosts.lock().iter().cloned().collect();
+        hosts
+            .into_iter()
+            .map(|host| {
+                let traffic = host_bytes.traffic(&host);
+                (host, traffic)
+            })
+            .collect()
+    }
+
+    /// Register a newly accepted connection in the list of active connections
+    pub(crate) fn open_connection(&self, id: &str, client_addr: SocketAddr) -> Arc<LiveConnection> {
+        let live = Arc::new(LiveConnection {
+            id: id.to_string(),
+            client_addr: Mutex::new(client_addr),
+            started: Instant::now(),
+            target: Mutex::new(None),
+            upstream_addr: Mutex::new(None),
+            connect_handshake: Mutex::new(None),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            abort: Mutex::new(None),
+        });
+        self.connections.lock().insert(id.to_string(), live.clone());
+        live
+    }
+
+    /// Remove a connection from the list of active connections
+    pub(crate) fn close_connection(&self, id: &str) {
+        self.connections.lock().remove(id);
+    }
+
+    /// Connections currently being served, oldest first
+    pub fn active_connections_snapshot(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self.connections.lock().values().map(|live| live.info()).collect();
+        connections.sort_by_key(|conn| std::cmp::Reverse(conn.age));
+        connections
+    }
+
+    /// Abort every active connection whose destination is `host`, returning how many were terminated
+    pub(crate) fn abort_connections_to(&self, host: &str) -> usize {
+        let targeted: Vec<Arc<LiveConnection>> = self
+            .connections
+            .lock()
+            .values()
+            .filter(|live| live.targets_host(host))
+            .cloned()
+            .collect();
+        let mut terminated = 0;
+        for live in targeted {
+            if let Some(abort) = live.abort.lock().as_ref() {
+                let info = live.info();
+                info!(
+                    conn_id = %info.id,
+                    "Terminating connection from {} to {}",
+                    info.client_addr,
+                    info.target.as_deref().unwrap_or_default()
+                );
+                abort.abort();
+                terminated += 1;
+            }
+        }
+        terminated
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn encode(&self) -> Result<String> {
+        if let Some((gauge, budget)) = &*self.egress_budget.lock() {
+            gauge.set(budget.available().max(0.0) as i64);
+        }
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+/// Buckets for size histograms, from 64 bytes to 64 MiB in steps of 4x
+fn size_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(64.0, 4.0, 11).expect("valid bucket parameters")
+}
+
+impl HostBytes {
+    /// Resolve the label to account a host under, registering it if there is room
+    fn label_for(&self, host: &str) -> String {
+        let host = host.to_ascii_lowercase();
+        let mut hosts = self.hosts.lock();
+        if hosts.contains(&host) {
+            return host;
+        }
+        if hosts.len() < self.max_hosts {
+            hosts.insert(host.clone());
+            return host;
+        }
+        hosts.insert(OTHER_HOSTS_LABEL.to_string());
+        OTHER_HOSTS_LABEL.to_string()
+    }
+
+    fn traffic(&self, label: &str) -> HostTraffic {
+        HostTraffic {
+            bytes_up: self.counter.with_label_values(&[label, "up"]).get(),
+            bytes_down: self.counter.with_label_values(&[label, "down"]).get(),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt::Write as _;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
+use tracing::debug;
+
+use crate::observer::{ConnectionOutcome, ProxyObserver};
+
+/// Prefix of every metric name
+const METRIC_PREFIX: &str = "forward_proxy";
+
+/// Largest datagram sent, small enough to avoid fragmentation on common networks
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Longest a metric line is held back waiting for more to batch with it
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Metric lines queued for sending before further ones are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// Observer pushing metrics to a StatsD server over UDP
+///
+/// Emits connection counts by outcome, relayed bytes, upstream errors, handler
+/// panics and HTTP request durations. Lines are batched into datagrams by a
+/// background thread, handlers only queue them and never wait; when the queue
+/// is full metrics are dropped.
+///
+/// With tags, the dogstatsd format is used and the outcome or status is sent
+/// as a tag, otherwise it becomes part of the metric name.
+pub struct StatsdObserver {
+    sender: SyncSender<String>,
+    tags: Option<String>,
+}
+
+impl StatsdObserver {
+    /// Send metrics to the StatsD server at `addr`
+    pub fn new(addr: &str) -> Result<Self> {
+        Self::with_tags(addr, Vec::new())
+    }
+
+    /// Send metrics to the dogstatsd server at `addr`, adding `tags` to every metric
+    pub fn with_tags(addr: &str, tags: Vec<(String, String)>) -> Result<Self> {
+        let target = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("StatsD address {} did not resolve", addr))?;
+        let bind_addr: SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(target)?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("statsd".to_string())
+            .spawn(move || send_batches(socket, receiver))?;
+
+        let tags = (!tags.is_empty()).then(|| {
+            tags.iter()
+                .map(|(key, value)| format!("{}:{}", key, value))
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        Ok(StatsdObserver { sender, tags })
+    }
+
+    /// Queue a metric line, sending `dimension` as a tag with tags enabled or as a name suffix otherwise
+    fn emit(&self, metric: &str, value: u64, kind: &str, dimension: Option<(&str, &str)>) {
+        let mut line = String::new();
+        let _ = match (&self.tags, dimension) {
+            (Some(tags), Some((name, dimension))) => {
+                write!(line, "{}.{}:{}|{}|#{}:{},{}", METRIC_PREFIX, metric, value, kind, name, dimension, tags)
+            }
+            (Some(tags), None) => write!(line, "{}.{}:{}|{}|#{}", METRIC_PREFIX, metric, value, kind, tags),
+            (None, Some((_, dimension))) => write!(line, "{}.{}.{}:{}|{}", METRIC_PREFIX, metric, dimension, value, kind),
+            (None, None) => write!(line, "{}.{}:{}|{}", METRIC_PREFIX, metric, value, kind),
+        };
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("StatsD queue is full, dropping metric {}", metric),
+            Err(TrySendError::Disconnected(_)) => debug!("StatsD sender has stopped, dropping metric {}", metric),
+        }
+    }
+}
+
+impl ProxyObserver for StatsdObserver {
+    fn on_panic(&self, _conn_id: &str, _peer: SocketAddr, _message: &str) {
+        self.emit("handler_panics", 1, "c", None);
+    }
+
+    fn on_connection_closed(&self, _conn_id: &str, _peer: SocketAddr, outcome: &ConnectionOutcome) {
+        self.emit("connections", 1, "c", Some(("outcome", outcome.label())));
+        match outcome {
+            ConnectionOutcome::Completed { bytes_in, bytes_out } => {
+                self.emit("bytes", *bytes_in, "c", Some(("direction", "up")));
+                self.emit("bytes", *bytes_out, "c", Some(("direction", "down")));
+            }
+            ConnectionOutcome::UpstreamError => self.emit("upstream_errors", 1, "c", None),
+            _ => {}
+        }
+    }
+
+    fn on_request_completed(&self, _conn_id: &str, _peer: SocketAddr, status: u16, duration: Duration) {
+        let status = status.to_string();
+        self.emit("request_duration", duration.as_millis() as u64, "ms", Some(("status", &status)));
+    }
+}
+
+/// Send queued metric lines, batching them into datagrams, until the observer is dropped
+///
+/// A batch is sent once the next line wouldn't fit or its first line has
+/// waited for `FLUSH_INTERVAL`.
+fn send_batches(socket: UdpSocket, receiver: mpsc::Receiver<String>) {
+    let mut batch = String::new();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(line) => {
+                if !batch.is_empty() && batch.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                    flush(&socket, &mut batch);
+                }
+                if batch.is_empty() {
+                    deadline = Some(Instant::now() + FLUSH_INTERVAL);
+                } else {
+                    batch.push('\n');
+                }
+                batch.push_str(&line);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&socket, &mut batch);
+                deadline = None;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                flush(&socket, &mut batch);
+                return;
+            }
+        }
+    }
+}
+
+fn flush(socket: &UdpSocket, batch: &mut String) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = socket.send(batch.as_bytes()) {
+        debug!("Failed to send StatsD metrics: {}", e);
+    }
+    batch.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Wait for the next datagram on `socket`, returning its lines
+    async fn receive(socket: &tokio::net::UdpSocket) -> Vec<String> {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let n = tokio::time::timeout(Duration::from_secs(3), socket.recv(&mut buf)).await.unwrap().unwrap();
+        String::from_utf8_lossy(&buf[..n]).lines().map(str::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn emits_metrics_of_a_completed_tunnel() {
+        let statsd = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (upstream, _) = tunnel_upstream().await;
+        let mut config = config(upstream);
+        config.observer = Some(std::sync::Arc::new(StatsdObserver::new(&statsd.local_addr().unwrap().to_string()).unwrap()));
+        let (_proxy, addr) = start(config).await;
+        let mut tunnel = connect_tunnel(addr, "example.test:443").await;
+        echo(&mut tunnel, b"ping").await;
+        drop(tunnel);
+
+        // The lines of the closed connection are batched into one datagram
+        assert_eq!(receive(&statsd).await, [
+            "forward_proxy.connections.completed:1|c",
+            "forward_proxy.bytes.up:4|c",
+            "forward_proxy.bytes.down:4|c",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn tags_metrics_in_dogstatsd_format() {
+        let statsd = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tags = vec![("env".to_string(), "test".to_string())];
+        let observer = StatsdObserver::with_tags(&statsd.local_addr().unwrap().to_string(), tags).unwrap();
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        observer.on_connection_closed("1", peer, &ConnectionOutcome::UpstreamError);
+        observer.on_request_completed("2", peer, 404, Duration::from_millis(25));
+
+        assert_eq!(receive(&statsd).await, [
+            "forward_proxy.connections:1|c|#outcome:upstream_error,env:test",
+            "forward_proxy.upstream_errors:1|c|#env:test",
+            "forward_proxy.request_duration:25|ms|#status:404,env:test",
+        ]);
+    }
+}
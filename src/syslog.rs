@@ -0,0 +1,328 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Application name reported in every syslog message
+const APP_NAME: &str = "forward-proxy";
+
+/// Syslog facility messages are filed under
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogFacility {
+    User,
+    #[default]
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    fn code(self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+/// Writer for a `tracing_subscriber::fmt` layer that sends each event to syslog
+///
+/// Every event becomes one RFC 5424 message, with its severity taken from the
+/// event's level. Messages are sent without blocking and dropped when the
+/// socket can't take them.
+#[derive(Clone)]
+pub struct SyslogWriter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: Socket,
+    facility: SyslogFacility,
+    hostname: String,
+    pid: u32,
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl SyslogWriter {
+    /// Send to the syslog daemon at `addr`
+    ///
+    /// An absolute path is a local Unix datagram socket such as `/dev/log`,
+    /// anything else a `host:port` reached over UDP.
+    pub fn connect(addr: &str, facility: SyslogFacility) -> Result<Self> {
+        let socket = if addr.starts_with('/') {
+            Self::connect_unix(Path::new(addr))?
+        } else {
+            let target = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow!("Syslog address {} did not resolve", addr))?;
+            let bind_addr: SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+            let socket = UdpSocket::bind(bind_addr)?;
+            socket.connect(target)?;
+            socket.set_nonblocking(true)?;
+            Socket::Udp(socket)
+        };
+        Ok(SyslogWriter {
+            inner: Arc::new(Inner {
+                socket,
+                facility,
+                hostname: hostname(),
+                pid: std::process::id(),
+            }),
+        })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(path: &Path) -> Result<Socket> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Socket::Unix(socket))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(path: &Path) -> Result<Socket> {
+        Err(anyhow!("Syslog socket {} requires Unix domain sockets", path.display()))
+    }
+
+    fn message(&self, level: Level) -> SyslogMessage {
+        SyslogMessage {
+            inner: self.inner.clone(),
+            severity: severity(level),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(*meta.level())
+    }
+}
+
+/// A single event being formatted, sent to syslog when dropped
+pub struct SyslogMessage {
+    inner: Arc<Inner>,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.buf);
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        let inner = &self.inner;
+        // No structured data, the message is the formatted event
+        let message = format!(
+            "<{}>1 {} {} {} {} - - {}",
+            inner.facility.code() * 8 + self.severity,
+            timestamp(SystemTime::now()),
+            inner.hostname,
+            APP_NAME,
+            inner.pid,
+            text
+        );
+        // Logging can't report its own failures, the message is lost
+        let _ = match &inner.socket {
+            Socket::Udp(socket) => socket.send(message.as_bytes()),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(message.as_bytes()),
+        };
+    }
+}
+
+/// Syslog severity for a tracing level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Name of this host, or the nil value when it can't be determined
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Format a time as an RFC 3339 UTC timestamp with microseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tracing_subscriber::EnvFilter;
+
+    /// UDP syslog listener on an ephemeral loopback port
+    fn listener() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        (socket, addr)
+    }
+
+    fn receive(socket: &UdpSocket) -> Option<String> {
+        let mut buf = [0; 2048];
+        let n = socket.recv(&mut buf).ok()?;
+        Some(String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+
+    #[test]
+    fn sends_events_in_rfc_5424_format() {
+        let (socket, addr) = listener();
+        let writer = SyslogWriter::connect(&addr, SyslogFacility::Local3).unwrap();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(writer).finish();
+        tracing::subscriber::with_default(subscriber, || tracing::warn!(target_addr = "example.test:443", "Upstream is slow"));
+
+        let message = receive(&socket).unwrap();
+        // local3 (19) * 8 + warning (4)
+        let rest = message.strip_prefix("<156>1 ").unwrap();
+        let fields: Vec<&str> = rest.splitn(6, ' ').collect();
+        let [timestamp, hostname, app_name, pid, msg_id, rest] = fields[..] else {
+            panic!("malformed message {:?}", message);
+        };
+        assert_eq!(timestamp.len(), "2024-01-01T00:00:00.000000Z".len(), "{}", timestamp);
+        assert!(timestamp.ends_with('Z') && timestamp.as_bytes()[10] == b'T', "{}", timestamp);
+        assert!(!hostname.is_empty());
+        assert_eq!(app_name, "forward-proxy");
+        assert_eq!(pid, std::process::id().to_string());
+        assert_eq!(msg_id, "-");
+        // No structured data, then the formatted event
+        let text = rest.strip_prefix("- ").unwrap();
+        assert!(text.contains("WARN"), "{}", text);
+        assert!(text.contains("Upstream is slow target_addr=\"example.test:443\""), "{}", text);
+    }
+
+    #[test]
+    fn honors_the_env_filter() {
+        let (socket, addr) = listener();
+        let writer = SyslogWriter::connect(&addr, SyslogFacility::Daemon).unwrap();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_env_filter(EnvFilter::new("error"))
+            .with_writer(writer)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Filtered out");
+            tracing::error!("Upstream unreachable");
+        });
+
+        // daemon (3) * 8 + error (3)
+        let message = receive(&socket).unwrap();
+        assert!(message.starts_with("<27>1 "), "{}", message);
+        assert!(message.ends_with("Upstream unreachable"), "{}", message);
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(receive(&socket), None);
+    }
+
+    #[tokio::test]
+    async fn delivers_a_record_for_each_completed_request() {
+        use crate::testing::{config, http_upstream, read_response, start};
+        use tokio::io::AsyncWriteExt;
+
+        let (socket, addr) = listener();
+        let writer = SyslogWriter::connect(&addr, SyslogFacility::Local0).unwrap();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(writer).finish();
+        // `#[tokio::test]` runs the proxy on this thread, under this subscriber
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let (upstream, _) = http_upstream(response).await;
+        let (_proxy, proxy_addr) = start(config(upstream)).await;
+        let mut client = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"GET http://example.test/ HTTP/1.1\r\nHost: example.test\r\n\r\n").await.unwrap();
+        read_response(&mut client).await;
+
+        let completed = std::iter::from_fn(|| receive(&socket)).find(|message| message.contains("HTTP request completed"));
+        let message = completed.expect("no record of the completed request");
+        // local0 (16) * 8 + informational (6)
+        assert!(message.starts_with("<134>1 "), "{}", message);
+        assert!(message.ends_with(&format!("sent {} bytes back to client", response.len())), "{}", message);
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        assert_eq!(timestamp(time), "2023-11-14T22:13:20.123456Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000000Z");
+    }
+}
@@ -0,0 +1,356 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::{ConnectionOutcome, NameResolver, ProxyConfig, ProxyHandle, ProxyObserver, ResolveFuture, spawn_proxy};
+
+/// Configuration for a proxy on an ephemeral loopback port in front of `upstream`
+pub(crate) fn config(upstream: SocketAddr) -> ProxyConfig {
+    ProxyConfig::new(
+        "127.0.0.1".to_string(),
+        0,
+        upstream.ip().to_string(),
+        upstream.port(),
+        String::new(),
+        String::new(),
+    )
+}
+
+/// Start a proxy and wait until it accepts connections
+pub(crate) async fn start(config: ProxyConfig) -> (ProxyHandle, SocketAddr) {
+    let mut handle = spawn_proxy(config).unwrap();
+    let addr = handle.ready().await.unwrap();
+    (handle, addr)
+}
+
+/// Listener on an ephemeral loopback port
+pub(crate) async fn listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+/// Read an HTTP head up to its blank line, leaving whatever follows unread
+///
+/// Returns what was read so far if the connection closes first.
+pub(crate) async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte).await {
+            Ok(1) => head.push(byte[0]),
+            _ => break,
+        }
+    }
+    String::from_utf8_lossy(&head).into_owned()
+}
+
+/// Upstream proxy answering every `CONNECT` with `200` and echoing the tunnel's bytes back
+///
+/// The heads of the `CONNECT` requests are sent on the returned channel.
+pub(crate) async fn tunnel_upstream() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let (listener, addr) = listener().await;
+    let (heads, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let heads = heads.clone();
+            tokio::spawn(async move {
+                let head = read_head(&mut stream).await;
+                let _ = heads.send(head);
+                stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Upstream proxy answering every request with `response`, keeping connections open
+///
+/// The heads of the requests are sent on the returned channel. Request bodies
+/// are not read.
+pub(crate) async fn http_upstream(response: &'static str) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let (addr, heads, _) = counted_http_upstream(response).await;
+    (addr, heads)
+}
+
+/// Like [`http_upstream`], also counting the connections accepted
+pub(crate) async fn counted_http_upstream(
+    response: &'static str,
+) -> (SocketAddr, mpsc::UnboundedReceiver<String>, Arc<AtomicUsize>) {
+    let (listener, addr) = listener().await;
+    let (heads, received) = mpsc::unbounded_channel();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let heads = heads.clone();
+            tokio::spawn(async move {
+                loop {
+                    let head = read_head(&mut stream).await;
+                    if head.is_empty() {
+                        break;
+                    }
+                    // The test may not care about the requests
+                    let _ = heads.send(head);
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, received, accepted)
+}
+
+/// Upstream accepting connections without ever answering, counting them
+pub(crate) async fn counting_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    let (listener, addr) = listener().await;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            streams.push(stream);
+        }
+    });
+    (addr, accepted)
+}
+
+/// Read a response whose body is framed by `Content-Length`, returning its head and body
+pub(crate) async fn read_response(stream: &mut TcpStream) -> (String, String) {
+    let head = read_head(stream).await;
+    let length = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse().unwrap())
+        })
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+/// Read until the peer closes the connection
+pub(crate) async fn read_to_end(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Open a tunnel to `target` through the proxy at `addr`, returning the client side of it
+pub(crate) async fn connect_tunnel(addr: SocketAddr, target: &str) -> TcpStream {
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let response = read_head(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "tunnel to {} refused: {}", target, response);
+    client
+}
+
+/// Send `data` through an echoing tunnel and wait for it to come back
+pub(crate) async fn echo(tunnel: &mut TcpStream, data: &[u8]) {
+    tunnel.write_all(data).await.unwrap();
+    let mut echoed = vec![0; data.len()];
+    tunnel.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+}
+
+/// TLS record holding a minimal ClientHello asking for `server_name`
+pub(crate) fn client_hello(server_name: &str) -> Vec<u8> {
+    let name = server_name.as_bytes();
+    let mut server_name_list = vec![0];
+    server_name_list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    server_name_list.extend_from_slice(name);
+    let mut extension = vec![0, 0];
+    extension.extend_from_slice(&(server_name_list.len() as u16 + 2).to_be_bytes());
+    extension.extend_from_slice(&(server_name_list.len() as u16).to_be_bytes());
+    extension.extend_from_slice(&server_name_list);
+
+    // TLS 1.2, a zero random, no session, one cipher suite and no compression
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[0; 32]);
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extension);
+
+    let mut message = vec![1];
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(&body);
+    let mut record = vec![0x16, 3, 1];
+    record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    record.extend_from_slice(&message);
+    record
+}
+
+/// Wait for `condition` to hold, failing the test after a few seconds
+pub(crate) async fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(tokio::time::Instant::now() < deadline, "condition not met in time");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Resolver answering every name with fixed addresses, or failing without any
+#[derive(Debug, Default)]
+pub(crate) struct StaticResolver {
+    ips: Vec<IpAddr>,
+    delay: Duration,
+    lookups: AtomicUsize,
+}
+
+impl StaticResolver {
+    pub(crate) fn new(ips: &[&str]) -> Arc<Self> {
+        Self::slow(ips, Duration::ZERO)
+    }
+
+    /// Resolver taking `delay` to answer each lookup
+    pub(crate) fn slow(ips: &[&str], delay: Duration) -> Arc<Self> {
+        Arc::new(StaticResolver {
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            delay,
+            lookups: AtomicUsize::new(0),
+        })
+    }
+
+    /// How many lookups were started so far
+    pub(crate) fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+}
+
+impl NameResolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            if self.ips.is_empty() {
+                return Err(anyhow!("no addresses for {}", host));
+            }
+            Ok(self.ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+/// Something a [`RecordingObserver`] was notified about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    Panic { conn_id: String, message: String },
+    Closed { conn_id: String, outcome: ConnectionOutcome },
+    Shadow { conn_id: String, status: Option<u16> },
+}
+
+/// Observer keeping every event it is notified about
+#[derive(Debug, Default)]
+pub(crate) struct RecordingObserver {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingObserver {
+    /// The events so far, in the order they happened
+    pub(crate) fn events(&self) -> Vec<Event> {
+        self.events.lock().clone()
+    }
+
+    /// Wait for the next connection to close, returning its outcome
+    pub(crate) async fn next_outcome(&self) -> ConnectionOutcome {
+        let mut outcome = None;
+        eventually(|| {
+            let mut events = self.events.lock();
+            let closed = events.iter().position(|event| matches!(event, Event::Closed { .. }));
+            if let Some(Event::Closed { outcome: closed, .. }) = closed.map(|index| events.remove(index)) {
+                outcome = Some(closed);
+            }
+            outcome.is_some()
+        })
+        .await;
+        outcome.unwrap()
+    }
+}
+
+impl ProxyObserver for RecordingObserver {
+    fn on_panic(&self, conn_id: &str, _peer: SocketAddr, message: &str) {
+        self.events.lock().push(Event::Panic {
+            conn_id: conn_id.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn on_connection_closed(&self, conn_id: &str, _peer: SocketAddr, outcome: &ConnectionOutcome) {
+        self.events.lock().push(Event::Closed {
+            conn_id: conn_id.to_string(),
+            outcome: *outcome,
+        });
+    }
+
+    fn on_shadow_completed(&self, conn_id: &str, _peer: SocketAddr, status: Option<u16>) {
+        self.events.lock().push(Event::Shadow {
+            conn_id: conn_id.to_string(),
+            status,
+        });
+    }
+}
+
+/// Leave Nagle on and set fixed buffer sizes, unlike the defaults
+#[cfg(target_os = "linux")]
+pub(crate) fn tuned_sockets(config: &mut ProxyConfig) {
+    config.tcp_nodelay = false;
+    config.so_sndbuf = Some(96 * 1024);
+    config.so_rcvbuf = Some(48 * 1024);
+}
+
+/// Check a socket has the options set by [`tuned_sockets`]
+#[cfg(target_os = "linux")]
+pub(crate) fn assert_tuned(stream: &TcpStream) {
+    assert!(!stream.nodelay().unwrap());
+    // Linux doubles the requested sizes to account for its bookkeeping
+    let socket = SockRef::from(stream);
+    assert_eq!(socket.send_buffer_size().unwrap(), 2 * 96 * 1024);
+    assert_eq!(socket.recv_buffer_size().unwrap(), 2 * 48 * 1024);
+}
+
+/// Log lines of events emitted on this thread until the guard is dropped
+///
+/// `#[tokio::test]` runs spawned tasks on the test's thread, so this covers the proxy too.
+pub(crate) fn capture_logs() -> (tracing::subscriber::DefaultGuard, Arc<Mutex<Vec<u8>>>) {
+    capture_logs_at(tracing::Level::INFO)
+}
+
+/// Like [`capture_logs`], down to `level`
+pub(crate) fn capture_logs_at(level: tracing::Level) -> (tracing::subscriber::DefaultGuard, Arc<Mutex<Vec<u8>>>) {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(move || LogWriter(writer.clone()))
+        .finish();
+    (tracing::subscriber::set_default(subscriber), logs)
+}
+
+/// Writer appending to a shared buffer
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use parking_lot::Mutex;
+use tokio::io::AsyncWrite;
+use tokio::time::{Instant, Sleep};
+
+/// How a per-connection bandwidth cap is shared between the two directions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Bytes in both directions count against a single cap
+    #[default]
+    Combined,
+    /// Each direction gets the full cap on its own
+    PerDirection,
+}
+
+/// Token bucket pacing a byte stream to a fixed rate
+///
+/// The bucket holds at most one period worth of bytes, one second unless
+/// created with [`TokenBucket::with_period`]. Consuming more than is
+/// available puts it into debt, which callers pay off by waiting.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self::with_period(bytes_per_sec, Duration::from_secs(1))
+    }
+
+    /// Create a bucket allowing `bytes` per `period`, refilled gradually
+    pub(crate) fn with_period(bytes: u64, period: Duration) -> Self {
+        let capacity = bytes.max(1) as f64;
+        TokenBucket {
+            bytes_per_sec: capacity / period.as_secs_f64().max(f64::MIN_POSITIVE),
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes that can be taken right now, negative while the bucket is in debt
+    pub(crate) fn available(&self) -> f64 {
+        self.refill(&mut self.state.lock())
+    }
+
+    fn refill(&self, state: &mut BucketState) -> f64 {
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.bytes_per_sec;
+        state.tokens = (state.tokens + refill).min(self.capacity);
+        state.updated = now;
+        state.tokens
+    }
+
+    /// How long until the bucket holds tokens again, zero if it has some now
+    pub(crate) fn time_until_available(&self) -> Duration {
+        let tokens = self.available();
+        if tokens > 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.bytes_per_sec)
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before sending more
+    pub(crate) fn consume(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Take `bytes` from every bucket, returning how long to wait until all their debts are paid off
+pub(crate) fn consume_all(buckets: &[Arc<TokenBucket>], bytes: usize) -> Duration {
+    buckets.iter().map(|bucket| bucket.consume(bytes)).max().unwrap_or_default()
+}
+
+/// Take `bytes` from every bucket and wait until their debts are paid off
+pub(crate) async fn pace_all(buckets: &[Arc<TokenBucket>], bytes: usize) {
+    let wait = consume_all(buckets, bytes);
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// The bandwidth caps applying to a single client connection
+///
+/// Besides the connection's own cap, this includes the proxy-wide egress
+/// budget shared by every connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionThrottle {
+    up: Vec<Arc<TokenBucket>>,
+    down: Vec<Arc<TokenBucket>>,
+    egress: Option<Arc<TokenBucket>>,
+}
+
+impl ConnectionThrottle {
+    /// Create the throttle for a connection, unlimited when `bytes_per_sec` is `None` and there is no `egress` budget
+    pub(crate) fn new(bytes_per_sec: Option<u64>, mode: ThrottleMode, egress: Option<&Arc<TokenBucket>>) -> Self {
+        let mut throttle = ConnectionThrottle::default();
+        if let Some(bytes_per_sec) = bytes_per_sec {
+            let up = Arc::new(TokenBucket::new(bytes_per_sec));
+            let down = match mode {
+                ThrottleMode::Combined => up.clone(),
+                ThrottleMode::PerDirection => Arc::new(TokenBucket::new(bytes_per_sec)),
+            };
+            throttle.up.push(up);
+            throttle.down.push(down);
+        }
+        if let Some(egress) = egress {
+            throttle.up.push(egress.clone());
+            throttle.down.push(egress.clone());
+            throttle.egress = Some(egress.clone());
+        }
+        throttle
+    }
+
+    /// Count bytes written outside a [`Throttled`] writer against the egress budget, without pausing
+    pub(crate) fn charge_egress(&self, bytes: usize) {
+        if let Some(egress) = &self.egress {
+            egress.consume(bytes);
+        }
+    }
+
+    /// Buckets for bytes sent by the client towards the destination
+    pub(crate) fn up(&self) -> &[Arc<TokenBucket>] {
+        &self.up
+    }
+
+    /// Buckets for bytes sent by the destination back to the client
+    pub(crate) fn down(&self) -> &[Arc<TokenBucket>] {
+        &self.down
+    }
+}
+
+/// Writer that paces the bytes written through it with token buckets
+pub(crate) struct Throttled<W> {
+    inner: W,
+    buckets: Vec<Arc<TokenBucket>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttled<W> {
+    pub(crate) fn new(inner: W, buckets: &[Arc<TokenBucket>]) -> Self {
+        Throttled {
+            inner,
+            buckets: buckets.to_vec(),
+            sleep: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(sleep) = &mut this.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.sleep = None;
+        }
+
+        let written = match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        let wait = consume_all(&this.buckets, written);
+        if !wait.is_zero() {
+            this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Settle any outstanding debt so the last write is paced too
+        let this = self.get_mut();
+        if let Some(sleep) = &mut this.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.sleep = None;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn bucket_goes_into_debt_beyond_its_capacity() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.consume(600), Duration::ZERO);
+        let wait = bucket.consume(900);
+        // 500 bytes of debt at 1000 bytes per second
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500), "{:?}", wait);
+        assert!(bucket.available() < 0.0);
+        assert!(!bucket.time_until_available().is_zero());
+    }
+
+    #[test]
+    fn combined_mode_shares_one_bucket() {
+        let combined = ConnectionThrottle::new(Some(1000), ThrottleMode::Combined, None);
+        assert!(Arc::ptr_eq(&combined.up()[0], &combined.down()[0]));
+        let separate = ConnectionThrottle::new(Some(1000), ThrottleMode::PerDirection, None);
+        assert!(!Arc::ptr_eq(&separate.up()[0], &separate.down()[0]));
+        assert!(ConnectionThrottle::new(None, ThrottleMode::Combined, None).up().is_empty());
+    }
+
+    #[tokio::test]
+    async fn throttled_writer_paces_to_the_rate() {
+        let buckets = [Arc::new(TokenBucket::new(20_000))];
+        let mut writer = Throttled::new(tokio::io::sink(), &buckets);
+        let started = Instant::now();
+        // The first 20 KB go out at once, the rest takes half a second
+        writer.write_all(&[0; 30_000]).await.unwrap();
+        writer.flush().await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,154 @@
+/// Record type of TLS handshake messages
+const HANDSHAKE: u8 = 0x16;
+
+/// Handshake type of a ClientHello
+const CLIENT_HELLO: u8 = 0x01;
+
+/// Extension carrying the server name (RFC 6066)
+const SERVER_NAME: u16 = 0x0000;
+
+/// Largest ClientHello inspected, longer ones are treated as invalid
+pub(crate) const MAX_CLIENT_HELLO: usize = 16 * 1024;
+
+/// What the first bytes a client sent through a tunnel say about its TLS ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ClientHello {
+    /// The bytes start a ClientHello, more are needed to parse it
+    Incomplete,
+    /// The bytes aren't the start of a TLS handshake
+    NotTls,
+    /// A ClientHello that can't be parsed or is larger than `MAX_CLIENT_HELLO`
+    Invalid,
+    /// A complete ClientHello, with the server name it asks for if any
+    Complete { server_name: Option<String> },
+}
+
+/// Parse the ClientHello at the start of `data`, which may span several records
+pub(crate) fn parse_client_hello(data: &[u8]) -> ClientHello {
+    if data.first().is_some_and(|&byte| byte != HANDSHAKE) {
+        return ClientHello::NotTls;
+    }
+    // Reassemble the handshake message from the records carrying it
+    let mut message = Vec::new();
+    let mut records = data;
+    loop {
+        let Some(header) = records.get(..5) else {
+            return ClientHello::Incomplete;
+        };
+        if header[0] != HANDSHAKE {
+            return ClientHello::Invalid;
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(payload) = records.get(5..5 + length) else {
+            return ClientHello::Incomplete;
+        };
+        message.extend_from_slice(payload);
+        records = &records[5 + length..];
+
+        if message.len() >= 4 {
+            if message[0] != CLIENT_HELLO {
+                return ClientHello::Invalid;
+            }
+            let body_length = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if body_length > MAX_CLIENT_HELLO {
+                return ClientHello::Invalid;
+            }
+            if message.len() >= 4 + body_length {
+                return match server_name(&message[4..4 + body_length]) {
+                    Some(server_name) => ClientHello::Complete { server_name },
+                    None => ClientHello::Invalid,
+                };
+            }
+        }
+        if message.len() > MAX_CLIENT_HELLO {
+            return ClientHello::Invalid;
+        }
+    }
+}
+
+/// The host name in the server name extension of a ClientHello body, `None` if the body is malformed
+fn server_name(body: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(body);
+    reader.take(2 + 32)?;
+    let session_id = reader.u8()? as usize;
+    reader.take(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.take(cipher_suites)?;
+    let compression_methods = reader.u8()? as usize;
+    reader.take(compression_methods)?;
+    if reader.0.is_empty() {
+        // No extensions at all
+        return Some(None);
+    }
+    let extensions_length = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_length)?);
+    while !extensions.0.is_empty() {
+        let extension = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let data = extensions.take(length)?;
+        if extension != SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(data);
+        let list_length = list.u16()? as usize;
+        let mut names = Reader(list.take(list_length)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            // Type 0 is a DNS host name, the only one defined
+            if name_type == 0 {
+                return Some(Some(String::from_utf8(name.to_vec()).ok()?));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+/// Cursor over the fields of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.0.len() {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::client_hello;
+
+    #[test]
+    fn finds_the_server_name_of_a_client_hello() {
+        let hello = client_hello("example.test");
+        let complete = ClientHello::Complete { server_name: Some("example.test".to_string()) };
+        assert_eq!(parse_client_hello(&hello), complete);
+        assert_eq!(parse_client_hello(&hello[..hello.len() - 1]), ClientHello::Incomplete);
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), ClientHello::NotTls);
+
+        // The same message split over two records
+        let message = &hello[5..];
+        let mut split = Vec::new();
+        for part in [&message[..10], &message[10..]] {
+            split.extend_from_slice(&[0x16, 3, 1]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(parse_client_hello(&split), complete);
+    }
+}